@@ -1,4 +1,4 @@
-use argparse::{ArgumentParser, Store, StoreOption};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
 use std::{fs::File, io::Read};
 use web_push::*;
 
@@ -9,6 +9,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
     let mut push_payload: Option<String> = None;
     let mut encoding: Option<String> = None;
     let mut ttl: Option<u32> = None;
+    let mut check = false;
 
     {
         let mut ap = ArgumentParser::new();
@@ -23,7 +24,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
         ap.refer(&mut encoding).add_option(
             &["-e", "--encoding"],
             StoreOption,
-            "Content Encoding Scheme : currently only accepts 'aes128gcm'. Defaults to 'aes128gcm'. Reserved for future standards.",
+            "Content Encoding Scheme : 'aes128gcm' or 'aesgcm'. Defaults to 'aes128gcm'.",
         );
 
         ap.refer(&mut subscription_info_file).add_option(
@@ -38,6 +39,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
         ap.refer(&mut ttl)
             .add_option(&["-t", "--time_to_live"], StoreOption, "TTL of the notification");
 
+        ap.refer(&mut check).add_option(
+            &["-c", "--check"],
+            StoreTrue,
+            "Only validate the notification, without sending it",
+        );
+
         ap.parse_args_or_exit();
     }
 
@@ -45,10 +52,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
     let mut contents = String::new();
     file.read_to_string(&mut contents).unwrap();
 
-    let ece_scheme = match encoding.as_deref() {
-        Some("aes128gcm") => ContentEncoding::Aes128Gcm,
+    let ece_scheme = match encoding {
+        Some(encoding) => encoding.parse().unwrap(),
         None => ContentEncoding::Aes128Gcm,
-        Some(_) => panic!("Content encoding can only be 'aes128gcm'"),
     };
 
     let subscription_info: SubscriptionInfo = serde_json::from_str(&contents).unwrap();
@@ -79,9 +85,16 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>
     };
 
     let client = WebPushClient::new()?;
+    let message = builder.build()?;
+
+    if check {
+        client.validate(&message)?;
+        println!("Valid, {} byte body", message.payload_len());
+        return Ok(());
+    }
 
-    let response = client.send(builder.build()?).await?;
-    println!("Sent: {:?}", response);
+    let response = client.send(message).await?;
+    println!("Sent, status {}, message {:?}", response.status, response.message_id());
 
     Ok(())
 }
@@ -1,18 +1,184 @@
 use std::convert::Infallible;
+use std::time::{Duration, Instant};
 
-use http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use futures_util::stream::{self, StreamExt};
+use http::header::{HeaderMap, CONTENT_LENGTH};
+use http::StatusCode;
+use hyper::client::connect::Connect;
 use hyper::{body::HttpBody, client::HttpConnector, Body, Client, Request as HttpRequest};
 use hyper_tls::HttpsConnector;
 
-use crate::clients::request_builder;
-use crate::error::{RetryAfter, WebPushError};
-use crate::message::WebPushMessage;
+#[cfg(any(test, feature = "test-util"))]
+use crate::clients::mock::MockTransport;
+use crate::clients::request_builder::{self, WebPushResponse};
+use crate::clients::{Observer, RedirectPolicy, RetryConfig, SendObserver, DEFAULT_TIMEOUT, MAX_RESPONSE_SIZE};
+use crate::error::WebPushError;
+use crate::http_ece::ContentEncoding;
+use crate::message::{parse_extra_headers, SubscriptionInfo, WebPushMessage, WebPushMessageBuilder};
+use crate::vapid::builder::PartialVapidSignatureBuilder;
+use crate::vapid::VapidSignature;
 
 /// An async client for sending the notification payload.
 ///
-/// This client is [`hyper`](https://crates.io/crates/hyper) based, and will only work in Tokio contexts.
-pub struct WebPushClient {
-    client: Client<HttpsConnector<HttpConnector>>,
+/// This client is [`hyper`](https://crates.io/crates/hyper) based, and will only work in Tokio contexts. It always
+/// uses HTTP/1.1, as hyper-tls doesn't negotiate HTTP/2, so unlike the isahc client it has no `with_http_version`.
+///
+/// It is generic over the hyper connector, so a custom client, for example one going through a proxy, can be
+/// used with [`WebPushClientBuilder::with_client`] or [`WebPushClient::from_client`].
+pub struct WebPushClient<C = HttpsConnector<HttpConnector>> {
+    transport: Transport<C>,
+    timeout: Duration,
+    retry: Option<RetryConfig>,
+    default_headers: HeaderMap,
+    observer: Option<Observer>,
+    redirect_policy: RedirectPolicy,
+}
+
+/// Where the requests go: the push service, or a mock recording them in tests.
+enum Transport<C> {
+    Hyper(Client<C>),
+    #[cfg(any(test, feature = "test-util"))]
+    Mock(MockTransport),
+}
+
+/// Configures a [`WebPushClient`], for when [`WebPushClient::new`] doesn't fit.
+///
+/// Hyper has no proxy support of its own. To use a proxy, build a hyper client with a proxying connector and pass it
+/// to [`WebPushClientBuilder::with_client`], which keeps the other options of the builder.
+///
+/// ```no_run
+/// # use web_push::*;
+/// # use std::time::Duration;
+/// # fn main() -> Result<(), WebPushError> {
+/// let client = WebPushClient::builder()
+///     .with_timeout(Duration::from_secs(5))
+///     .with_retry(RetryConfig::default())
+///     .with_header("X-Api-Key", "secret")
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct WebPushClientBuilder<C = HttpsConnector<HttpConnector>> {
+    connection: Connection<C>,
+    timeout: Duration,
+    retry: Option<RetryConfig>,
+    headers: Vec<(String, String)>,
+    observer: Option<Observer>,
+    redirect_policy: RedirectPolicy,
+}
+
+/// The hyper client a builder sends with: the default one, made with the timeout of the builder, or one given.
+#[derive(Debug, Clone)]
+enum Connection<C> {
+    Default(fn(Duration) -> Client<C>),
+    Custom(Client<C>),
+}
+
+impl Default for WebPushClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebPushClientBuilder {
+    /// Creates a builder with the defaults of [`WebPushClient::new`].
+    pub fn new() -> WebPushClientBuilder {
+        WebPushClientBuilder {
+            connection: Connection::Default(default_client),
+            timeout: DEFAULT_TIMEOUT,
+            retry: None,
+            headers: Vec::new(),
+            observer: None,
+            redirect_policy: RedirectPolicy::default(),
+        }
+    }
+}
+
+impl<C> WebPushClientBuilder<C> {
+    /// Sends with an existing hyper client instead of the default one, for sharing its connection pool and
+    /// configuration, like a proxying connector or TLS settings. The timeout of the builder still applies to each
+    /// request, but it can't set the connect timeout of a given client.
+    pub fn with_client<D>(self, client: Client<D>) -> WebPushClientBuilder<D> {
+        WebPushClientBuilder {
+            connection: Connection::Custom(client),
+            timeout: self.timeout,
+            retry: self.retry,
+            headers: self.headers,
+            observer: self.observer,
+            redirect_policy: self.redirect_policy,
+        }
+    }
+
+    /// Sets how long a request may take, see [`WebPushClient::with_timeout`]. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> WebPushClientBuilder<C> {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retries failed requests, see [`WebPushClient::with_retry`]. Requests are not retried by default.
+    pub fn with_retry(mut self, retry: RetryConfig) -> WebPushClientBuilder<C> {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Adds a header sent with every message, like the API key of a proxy. Headers added to a message with
+    /// [`WebPushMessageBuilder::add_header`] replace the default header with the same name.
+    ///
+    /// Headers used by the protocol can't be set. Building fails with [`WebPushError::InvalidHeader`] if given
+    /// one of those, or an invalid name or value.
+    pub fn with_header(mut self, name: &str, value: &str) -> WebPushClientBuilder<C> {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Observes every message sent, see [`WebPushClient::with_observer`].
+    pub fn with_observer<O: SendObserver + 'static>(mut self, observer: O) -> WebPushClientBuilder<C> {
+        self.observer = Some(Observer::new(observer));
+        self
+    }
+
+    /// Sets what to do with redirects, see [`WebPushClient::with_redirect_policy`]. Redirects fail sending by
+    /// default.
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> WebPushClientBuilder<C> {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+
+    /// Creates the client. Fails only for an invalid header.
+    pub fn build(self) -> Result<WebPushClient<C>, WebPushError> {
+        let default_headers = parse_extra_headers(&self.headers)?;
+
+        let mut client = self.build_client();
+        client.default_headers = default_headers;
+
+        Ok(client)
+    }
+
+    fn build_client(self) -> WebPushClient<C> {
+        let client = match self.connection {
+            Connection::Default(make_client) => make_client(self.timeout),
+            Connection::Custom(client) => client,
+        };
+
+        WebPushClient {
+            transport: Transport::Hyper(client),
+            timeout: self.timeout,
+            retry: self.retry,
+            default_headers: HeaderMap::new(),
+            observer: self.observer,
+            redirect_policy: self.redirect_policy,
+        }
+    }
+}
+
+/// Creates the default hyper client, connecting over TLS with `timeout` as the connect timeout.
+fn default_client(timeout: Duration) -> Client<HttpsConnector<HttpConnector>> {
+    let mut connector = HttpConnector::new();
+    connector.enforce_http(false);
+    connector.set_connect_timeout(Some(timeout));
+
+    Client::builder().build(HttpsConnector::new_with_connector(connector))
 }
 
 impl Default for WebPushClient {
@@ -22,62 +188,307 @@ impl Default for WebPushClient {
 }
 
 impl WebPushClient {
-    /// Creates a new client.
+    /// Creates a new client, with requests timing out after 10 seconds.
     pub fn new() -> Result<WebPushClient, Infallible> {
+        Self::with_timeout(DEFAULT_TIMEOUT)
+    }
+
+    /// Creates a new client, with requests timing out after `timeout`. The timeout covers both
+    /// connecting and receiving the full response.
+    pub fn with_timeout(timeout: Duration) -> Result<WebPushClient, Infallible> {
         //This method can never fail, but returns error to match API of the isahc client.
-        Ok(WebPushClient {
-            client: Client::builder().build(HttpsConnector::new()),
-        })
+        Ok(WebPushClientBuilder::new().with_timeout(timeout).build_client())
     }
 
-    /// Sends a notification. Never times out.
-    pub async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
-        trace!("Message: {:?}", message);
+    /// Creates a builder for a client with more options, like default headers.
+    pub fn builder() -> WebPushClientBuilder {
+        WebPushClientBuilder::new()
+    }
 
-        let request: HttpRequest<Body> = request_builder::build_request(message);
+    /// Creates a client that sends nothing, but records the requests in `transport` and answers with the
+    /// responses queued in it. For testing code that sends notifications.
+    ///
+    /// Only available with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn mock(transport: MockTransport) -> WebPushClient {
+        WebPushClient {
+            transport: Transport::Mock(transport),
+            timeout: DEFAULT_TIMEOUT,
+            retry: None,
+            default_headers: HeaderMap::new(),
+            observer: None,
+            redirect_policy: RedirectPolicy::default(),
+        }
+    }
+}
 
-        debug!("Request: {:?}", request);
+impl<C> WebPushClient<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Creates a new client from an existing hyper client, for sharing its connection pool and
+    /// configuration, like proxies or TLS settings. Requests time out after 10 seconds; use
+    /// [`WebPushClientBuilder::with_client`] to set the timeout and other options as well.
+    pub fn from_client(client: Client<C>) -> WebPushClient<C> {
+        WebPushClient {
+            transport: Transport::Hyper(client),
+            timeout: DEFAULT_TIMEOUT,
+            retry: None,
+            default_headers: HeaderMap::new(),
+            observer: None,
+            redirect_policy: RedirectPolicy::default(),
+        }
+    }
 
-        let requesting = self.client.request(request);
+    /// Makes the client retry timeouts, connection failures and server errors with an exponential backoff, waiting
+    /// at least as long as the push service asks with `Retry-After`. See [`RetryConfig`] for details.
+    pub fn with_retry(mut self, retry: RetryConfig) -> WebPushClient<C> {
+        self.retry = Some(retry);
+        self
+    }
 
-        let response = requesting.await?;
+    /// Calls `observer` after sending each message, with the origin of the endpoint, the final status and how long
+    /// it took. See [`SendObserver`] for details.
+    pub fn with_observer<O: SendObserver + 'static>(mut self, observer: O) -> WebPushClient<C> {
+        self.observer = Some(Observer::new(observer));
+        self
+    }
 
-        trace!("Response: {:?}", response);
+    /// Sets what to do when the push service redirects a message to another endpoint. By default, sending fails
+    /// with [`WebPushError::Redirected`], giving the new endpoint to store. See [`RedirectPolicy`] for following
+    /// redirects instead.
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> WebPushClient<C> {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+
+    /// Checks that the message can be sent, without sending anything. The message gets the default headers of
+    /// the client and its request is assembled like with [`WebPushClient::send`], failing like
+    /// [`WebPushMessage::validate`]. Useful for a dry run, like a `--check` flag validating a configuration.
+    pub fn validate(&self, message: &WebPushMessage) -> Result<(), WebPushError> {
+        super::validate(message, &self.default_headers)
+    }
+
+    /// Sends a notification. Fails with [`WebPushError::Timeout`] if the request takes longer than
+    /// the client timeout. If retries are enabled, only the error of the last attempt is returned.
+    ///
+    /// On success, the [`WebPushResponse`] has the status and, if given, the `Location` of the message.
+    ///
+    /// Dropping the returned future cancels the request, closing its connection. The observer of the client, if any,
+    /// isn't called for a dropped send.
+    pub async fn send(&self, message: WebPushMessage) -> Result<WebPushResponse, WebPushError> {
+        let message = super::add_default_headers(message, &self.default_headers);
+
+        #[cfg(feature = "tracing")]
+        let span = super::send_span(&message);
+
+        let observing = self
+            .observer
+            .as_ref()
+            .map(|observer| (observer, super::origin(&message.endpoint), Instant::now()));
+
+        let mut status = None;
+        let sending = self.send_with_redirects(message, &mut status);
+
+        #[cfg(feature = "tracing")]
+        let sending = tracing::Instrument::instrument(sending, span);
+
+        let result = sending.await;
+
+        if let Some((observer, origin, start)) = observing {
+            observer.notify(&origin, status, &result, start.elapsed());
+        }
+
+        result
+    }
+
+    /// Encrypts `payload` for the subscription, signs it with `vapid_signature` and sends it, in one go. Use
+    /// [`WebPushMessageBuilder`] instead for setting the TTL, urgency or topic.
+    pub async fn send_payload(
+        &self,
+        subscription_info: &SubscriptionInfo,
+        vapid_signature: VapidSignature,
+        encoding: ContentEncoding,
+        payload: &[u8],
+    ) -> Result<WebPushResponse, WebPushError> {
+        let mut builder = WebPushMessageBuilder::new(subscription_info)?;
+        builder.set_payload(encoding, payload);
+        builder.set_vapid_signature(vapid_signature);
+
+        self.send(builder.build()?).await
+    }
+
+    /// Sends the same payload to many subscriptions, like all the devices of one user, with at most `concurrency`
+    /// requests in flight at once. The payload is signed with `vapid_builder` and encrypted separately for each
+    /// subscription.
+    ///
+    /// The results are in the same order as the subscriptions. A subscription that can't be signed or encrypted
+    /// for, like one with invalid keys, gets its error in the results without stopping the others.
+    pub async fn send_payload_to_all(
+        &self,
+        subscriptions: &[SubscriptionInfo],
+        vapid_builder: &PartialVapidSignatureBuilder,
+        encoding: ContentEncoding,
+        payload: &[u8],
+        concurrency: usize,
+    ) -> Vec<Result<WebPushResponse, WebPushError>> {
+        stream::iter(subscriptions)
+            .map(|subscription_info| async move {
+                let message = super::build_for_subscription(subscription_info, vapid_builder, encoding, payload)?;
+
+                self.send(message).await
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Sends many notifications, with at most `concurrency` requests in flight at once. The results are
+    /// in the same order as the messages, and a failed message doesn't stop the others from being sent.
+    pub async fn send_all(
+        &self,
+        messages: Vec<WebPushMessage>,
+        concurrency: usize,
+    ) -> Vec<Result<WebPushResponse, WebPushError>> {
+        stream::iter(messages)
+            .map(|message| self.send(message))
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
+
+    /// Sends a notification, blocking the current thread until done. Behaves exactly like
+    /// [`WebPushClient::send`], but doesn't need an async executor.
+    ///
+    /// This starts a single threaded Tokio runtime, and will panic if called from within another runtime.
+    #[cfg(feature = "blocking")]
+    pub fn send_blocking(&self, message: WebPushMessage) -> Result<WebPushResponse, WebPushError> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+        runtime.block_on(self.send(message))
+    }
+
+    async fn send_with_redirects(
+        &self,
+        mut message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        if let RedirectPolicy::Error = self.redirect_policy {
+            return self.send_with_retry(message, status).await;
+        }
+
+        let mut redirects = 0;
 
-        let retry_after = response
-            .headers()
-            .get(RETRY_AFTER)
-            .and_then(|ra| ra.to_str().ok())
-            .and_then(|ra| RetryAfter::from_str(ra));
+        loop {
+            let location = match self.send_with_retry(message.clone(), status).await {
+                Err(WebPushError::Redirected(location)) => location,
+                result => return result,
+            };
 
-        let response_status = response.status();
-        trace!("Response status: {}", response_status);
+            message = self.redirect_policy.redirect(message, &location, redirects)?;
+            redirects += 1;
 
-        let content_length: usize = response
-            .headers()
+            debug!(
+                "Following redirect {} to {}",
+                redirects,
+                super::origin(&message.endpoint)
+            );
+        }
+    }
+
+    async fn send_with_retry(
+        &self,
+        message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        let retry = match &self.retry {
+            Some(retry) => retry,
+            None => return self.send_with_timeout(message, status).await,
+        };
+
+        let mut retries = 0;
+
+        loop {
+            let error = match self.send_with_timeout(message.clone(), status).await {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            match retry.delay(retries, &error) {
+                Some(delay) => {
+                    debug!("Retrying in {:?} after error: {}", delay, error);
+
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(retries, ?delay, %error, "Retrying");
+
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    async fn send_with_timeout(
+        &self,
+        message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        *status = None;
+
+        tokio::time::timeout(self.timeout, self.send_message(message, status))
+            .await
+            .map_err(|_| WebPushError::Timeout)?
+    }
+
+    /// Sends the message once, keeping the status of the response in `status`.
+    async fn send_message(
+        &self,
+        message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        //Without the mock, there is only one transport.
+        #[allow(clippy::infallible_destructuring_match)]
+        let client = match &self.transport {
+            Transport::Hyper(client) => client,
+            #[cfg(any(test, feature = "test-util"))]
+            Transport::Mock(mock) => return mock.send(message, status),
+        };
+
+        let endpoint = message.endpoint.clone();
+        let request: HttpRequest<Body> = request_builder::build_request(message);
+
+        //Only the origin is logged, the rest of the endpoint and the headers hold the push token and credentials.
+        debug!("Sending POST to {}", super::origin(&endpoint));
+
+        let requesting = client.request(request);
+
+        let response = requesting.await?;
+
+        let (parts, mut chunks) = response.into_parts();
+        //The push service answered, even if reading the body fails below.
+        *status = Some(parts.status);
+
+        let content_length: usize = parts
+            .headers
             .get(CONTENT_LENGTH)
             .and_then(|s| s.to_str().ok())
             .and_then(|s| s.parse().ok())
-            .unwrap_or(0);
+            .unwrap_or(0)
+            .min(MAX_RESPONSE_SIZE);
 
         let mut body: Vec<u8> = Vec::with_capacity(content_length);
-        let mut chunks = response.into_body();
 
+        //Only read as much of the body as is needed for diagnostics.
         while let Some(chunk) = chunks.data().await {
             body.extend(&chunk?);
-        }
-        trace!("Body: {:?}", body);
-
-        trace!("Body text: {:?}", std::str::from_utf8(&body));
 
-        let response = request_builder::parse_response(response_status, body.to_vec());
-
-        debug!("Response: {:?}", response);
-
-        if let Err(WebPushError::ServerError(None)) = response {
-            Err(WebPushError::ServerError(retry_after))
-        } else {
-            Ok(response?)
+            if body.len() >= MAX_RESPONSE_SIZE {
+                body.truncate(MAX_RESPONSE_SIZE);
+                break;
+            }
         }
+
+        super::handle_response(&endpoint, parts.status, &parts.headers, body)
     }
 }
@@ -1,17 +1,170 @@
+use std::io::ErrorKind;
+use std::time::{Duration, Instant};
+
 use futures_lite::AsyncReadExt;
-use http::header::{CONTENT_LENGTH, RETRY_AFTER};
+use futures_util::stream::{self, StreamExt};
+use http::header::{HeaderMap, CONTENT_LENGTH};
+use http::{StatusCode, Uri};
+use isahc::config::{Configurable, VersionNegotiation};
 use isahc::HttpClient;
 
-use crate::clients::request_builder;
-use crate::error::{RetryAfter, WebPushError};
-use crate::message::WebPushMessage;
+#[cfg(any(test, feature = "test-util"))]
+use crate::clients::mock::MockTransport;
+use crate::clients::request_builder::{self, WebPushResponse};
+use crate::clients::{Observer, RedirectPolicy, RetryConfig, SendObserver, DEFAULT_TIMEOUT, MAX_RESPONSE_SIZE};
+use crate::error::WebPushError;
+use crate::http_ece::ContentEncoding;
+use crate::message::{parse_extra_headers, SubscriptionInfo, WebPushMessage, WebPushMessageBuilder};
+use crate::vapid::builder::PartialVapidSignatureBuilder;
+use crate::vapid::VapidSignature;
 
 /// An async client for sending the notification payload. This client is expensive to create, and
 /// should be reused.
 ///
 /// This client is built on [`isahc`](https://crates.io/crates/isahc), and will therefore work on any async executor.
 pub struct WebPushClient {
-    client: HttpClient,
+    transport: Transport,
+    retry: Option<RetryConfig>,
+    http_version: HttpVersion,
+    default_headers: HeaderMap,
+    observer: Option<Observer>,
+    redirect_policy: RedirectPolicy,
+}
+
+/// Which HTTP version the client uses to talk to the push service, see [`WebPushClient::with_http_version`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HttpVersion {
+    /// Uses HTTP/2 if the push service offers it when connecting, and HTTP/1.1 otherwise. This is the default.
+    #[default]
+    Negotiate,
+    /// Always uses HTTP/1.1, for push services that misbehave with HTTP/2.
+    Http1,
+    /// Always uses HTTP/2, without falling back to HTTP/1.1.
+    Http2,
+}
+
+/// Where the requests go: the push service, or a mock recording them in tests.
+enum Transport {
+    Isahc(HttpClient),
+    #[cfg(any(test, feature = "test-util"))]
+    Mock(MockTransport),
+}
+
+/// Configures a [`WebPushClient`], for when [`WebPushClient::new`] doesn't fit.
+///
+/// ```no_run
+/// # use web_push::*;
+/// # use std::time::Duration;
+/// # fn main() -> Result<(), WebPushError> {
+/// let client = WebPushClient::builder()
+///     .with_timeout(Duration::from_secs(5))
+///     .with_retry(RetryConfig::default())
+///     .with_proxy("http://proxy.internal:3128".parse().unwrap())
+///     .with_header("X-Api-Key", "secret")
+///     .build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct WebPushClientBuilder {
+    timeout: Duration,
+    retry: Option<RetryConfig>,
+    http_version: HttpVersion,
+    proxy: Option<Uri>,
+    headers: Vec<(String, String)>,
+    observer: Option<Observer>,
+    redirect_policy: RedirectPolicy,
+}
+
+impl Default for WebPushClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WebPushClientBuilder {
+    /// Creates a builder with the defaults of [`WebPushClient::new`].
+    pub fn new() -> WebPushClientBuilder {
+        WebPushClientBuilder {
+            timeout: DEFAULT_TIMEOUT,
+            retry: None,
+            http_version: HttpVersion::default(),
+            proxy: None,
+            headers: Vec::new(),
+            observer: None,
+            redirect_policy: RedirectPolicy::default(),
+        }
+    }
+
+    /// Sets how long a request may take, see [`WebPushClient::with_timeout`]. Defaults to 10 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> WebPushClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Retries failed requests, see [`WebPushClient::with_retry`]. Requests are not retried by default.
+    pub fn with_retry(mut self, retry: RetryConfig) -> WebPushClientBuilder {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Sets the HTTP version, see [`WebPushClient::with_http_version`].
+    pub fn with_http_version(mut self, http_version: HttpVersion) -> WebPushClientBuilder {
+        self.http_version = http_version;
+        self
+    }
+
+    /// Sends all requests through an HTTP proxy. Without this, the proxy of the `http_proxy` and `https_proxy`
+    /// environment variables is used, if set.
+    pub fn with_proxy(mut self, proxy: Uri) -> WebPushClientBuilder {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Adds a header sent with every message, like the API key of a proxy. Headers added to a message with
+    /// [`WebPushMessageBuilder::add_header`] replace the default header with the same name.
+    ///
+    /// Headers used by the protocol can't be set. Building fails with [`WebPushError::InvalidHeader`] if given
+    /// one of those, or an invalid name or value.
+    pub fn with_header(mut self, name: &str, value: &str) -> WebPushClientBuilder {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Observes every message sent, see [`WebPushClient::with_observer`].
+    pub fn with_observer<O: SendObserver + 'static>(mut self, observer: O) -> WebPushClientBuilder {
+        self.observer = Some(Observer::new(observer));
+        self
+    }
+
+    /// Sets what to do with redirects, see [`WebPushClient::with_redirect_policy`]. Redirects fail sending by
+    /// default.
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> WebPushClientBuilder {
+        self.redirect_policy = redirect_policy;
+        self
+    }
+
+    /// Creates the client. Can fail under resource depletion, or for an invalid header.
+    pub fn build(self) -> Result<WebPushClient, WebPushError> {
+        let default_headers = parse_extra_headers(&self.headers)?;
+
+        let mut builder = HttpClient::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.timeout);
+
+        if let Some(proxy) = self.proxy {
+            builder = builder.proxy(Some(proxy));
+        }
+
+        Ok(WebPushClient {
+            transport: Transport::Isahc(builder.build()?),
+            retry: self.retry,
+            http_version: self.http_version,
+            default_headers,
+            observer: self.observer,
+            redirect_policy: self.redirect_policy,
+        })
+    }
 }
 
 impl Default for WebPushClient {
@@ -21,63 +174,310 @@ impl Default for WebPushClient {
 }
 
 impl WebPushClient {
-    /// Creates a new client. Can fail under resource depletion.
+    /// Creates a new client, with requests timing out after 10 seconds. Can fail under resource depletion.
     pub fn new() -> Result<WebPushClient, WebPushError> {
-        Ok(WebPushClient {
-            client: HttpClient::new()?,
-        })
+        Self::with_timeout(DEFAULT_TIMEOUT)
     }
 
-    /// Sends a notification. Never times out.
-    pub async fn send(&self, message: WebPushMessage) -> Result<(), WebPushError> {
-        trace!("Message: {:?}", message);
+    /// Creates a new client, with requests timing out after `timeout`. The timeout covers both
+    /// connecting and receiving the full response. Can fail under resource depletion.
+    pub fn with_timeout(timeout: Duration) -> Result<WebPushClient, WebPushError> {
+        WebPushClientBuilder::new().with_timeout(timeout).build()
+    }
 
-        let request = request_builder::build_request::<isahc::AsyncBody>(message);
+    /// Creates a builder for a client with more options, like a proxy or default headers.
+    pub fn builder() -> WebPushClientBuilder {
+        WebPushClientBuilder::new()
+    }
 
-        trace!("Request: {:?}", request);
+    /// Creates a new client from an existing isahc client, for sharing its connection pool and
+    /// configuration, like proxies, TLS settings or timeouts. Leave redirects to [`RedirectPolicy`] instead of the
+    /// isahc client, which would follow them with the VAPID token of the old origin.
+    pub fn from_client(client: HttpClient) -> WebPushClient {
+        WebPushClient {
+            transport: Transport::Isahc(client),
+            retry: None,
+            http_version: HttpVersion::default(),
+            default_headers: HeaderMap::new(),
+            observer: None,
+            redirect_policy: RedirectPolicy::default(),
+        }
+    }
 
-        let requesting = self.client.send_async(request);
+    /// Creates a client that sends nothing, but records the requests in `transport` and answers with the
+    /// responses queued in it. For testing code that sends notifications.
+    ///
+    /// Only available with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn mock(transport: MockTransport) -> WebPushClient {
+        WebPushClient {
+            transport: Transport::Mock(transport),
+            retry: None,
+            http_version: HttpVersion::default(),
+            default_headers: HeaderMap::new(),
+            observer: None,
+            redirect_policy: RedirectPolicy::default(),
+        }
+    }
 
-        let response = requesting.await?;
+    /// Makes the client retry timeouts, connection failures and server errors with an exponential backoff, waiting
+    /// at least as long as the push service asks with `Retry-After`. See [`RetryConfig`] for details.
+    pub fn with_retry(mut self, retry: RetryConfig) -> WebPushClient {
+        self.retry = Some(retry);
+        self
+    }
 
-        trace!("Response: {:?}", response);
+    /// Makes the client use a specific HTTP version instead of negotiating one with the push service. This is
+    /// applied to each request, so it also works for clients made with [`WebPushClient::from_client`].
+    pub fn with_http_version(mut self, http_version: HttpVersion) -> WebPushClient {
+        self.http_version = http_version;
+        self
+    }
 
-        let retry_after = response
-            .headers()
-            .get(RETRY_AFTER)
-            .and_then(|ra| ra.to_str().ok())
-            .and_then(|ra| RetryAfter::from_str(ra));
+    /// Calls `observer` after sending each message, with the origin of the endpoint, the final status and how long
+    /// it took. See [`SendObserver`] for details.
+    pub fn with_observer<O: SendObserver + 'static>(mut self, observer: O) -> WebPushClient {
+        self.observer = Some(Observer::new(observer));
+        self
+    }
 
-        let response_status = response.status();
-        trace!("Response status: {}", response_status);
+    /// Sets what to do when the push service redirects a message to another endpoint. By default, sending fails
+    /// with [`WebPushError::Redirected`], giving the new endpoint to store. See [`RedirectPolicy`] for following
+    /// redirects instead.
+    pub fn with_redirect_policy(mut self, redirect_policy: RedirectPolicy) -> WebPushClient {
+        self.redirect_policy = redirect_policy;
+        self
+    }
 
-        let content_length: usize = response
-            .headers()
-            .get(CONTENT_LENGTH)
-            .and_then(|s| s.to_str().ok())
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(0);
+    /// Encrypts `payload` for the subscription, signs it with `vapid_signature` and sends it, in one go. Use
+    /// [`WebPushMessageBuilder`] instead for setting the TTL, urgency or topic.
+    pub async fn send_payload(
+        &self,
+        subscription_info: &SubscriptionInfo,
+        vapid_signature: VapidSignature,
+        encoding: ContentEncoding,
+        payload: &[u8],
+    ) -> Result<WebPushResponse, WebPushError> {
+        let mut builder = WebPushMessageBuilder::new(subscription_info)?;
+        builder.set_payload(encoding, payload);
+        builder.set_vapid_signature(vapid_signature);
 
-        let mut body: Vec<u8> = Vec::with_capacity(content_length);
-        let mut chunks = response.into_body();
+        self.send(builder.build()?).await
+    }
+
+    /// Sends the same payload to many subscriptions, like all the devices of one user, with at most `concurrency`
+    /// requests in flight at once. The payload is signed with `vapid_builder` and encrypted separately for each
+    /// subscription.
+    ///
+    /// The results are in the same order as the subscriptions. A subscription that can't be signed or encrypted
+    /// for, like one with invalid keys, gets its error in the results without stopping the others.
+    pub async fn send_payload_to_all(
+        &self,
+        subscriptions: &[SubscriptionInfo],
+        vapid_builder: &PartialVapidSignatureBuilder,
+        encoding: ContentEncoding,
+        payload: &[u8],
+        concurrency: usize,
+    ) -> Vec<Result<WebPushResponse, WebPushError>> {
+        stream::iter(subscriptions)
+            .map(|subscription_info| async move {
+                let message = super::build_for_subscription(subscription_info, vapid_builder, encoding, payload)?;
+
+                self.send(message).await
+            })
+            .buffered(concurrency.max(1))
+            .collect()
+            .await
+    }
 
-        chunks
-            .read_to_end(&mut body)
+    /// Sends many notifications, with at most `concurrency` requests in flight at once. The results are
+    /// in the same order as the messages, and a failed message doesn't stop the others from being sent.
+    pub async fn send_all(
+        &self,
+        messages: Vec<WebPushMessage>,
+        concurrency: usize,
+    ) -> Vec<Result<WebPushResponse, WebPushError>> {
+        stream::iter(messages)
+            .map(|message| self.send(message))
+            .buffered(concurrency.max(1))
+            .collect()
             .await
-            .map_err(|_| WebPushError::InvalidResponse)?;
+    }
+
+    /// Sends a notification, blocking the current thread until done. Behaves exactly like
+    /// [`WebPushClient::send`], but doesn't need an async executor.
+    #[cfg(feature = "blocking")]
+    pub fn send_blocking(&self, message: WebPushMessage) -> Result<WebPushResponse, WebPushError> {
+        //Isahc drives the request on its own thread, so no runtime is needed to wait for it.
+        futures_lite::future::block_on(self.send(message))
+    }
+
+    /// Checks that the message can be sent, without sending anything. The message gets the default headers of
+    /// the client and its request is assembled like with [`WebPushClient::send`], failing like
+    /// [`WebPushMessage::validate`]. Useful for a dry run, like a `--check` flag validating a configuration.
+    pub fn validate(&self, message: &WebPushMessage) -> Result<(), WebPushError> {
+        super::validate(message, &self.default_headers)
+    }
+
+    /// Sends a notification. Fails with [`WebPushError::Timeout`] if the request takes longer than
+    /// the client timeout. If retries are enabled, only the error of the last attempt is returned.
+    ///
+    /// On success, the [`WebPushResponse`] has the status and, if given, the `Location` of the message.
+    ///
+    /// Dropping the returned future cancels the request. Isahc checks for this whenever the push service sends
+    /// or accepts data, so the connection of a request waiting on a silent push service is closed at the latest
+    /// when the client timeout expires. The observer of the client, if any, isn't called for a dropped send.
+    pub async fn send(&self, message: WebPushMessage) -> Result<WebPushResponse, WebPushError> {
+        let message = super::add_default_headers(message, &self.default_headers);
+
+        #[cfg(feature = "tracing")]
+        let span = super::send_span(&message);
+
+        let observing = self
+            .observer
+            .as_ref()
+            .map(|observer| (observer, super::origin(&message.endpoint), Instant::now()));
+
+        let mut status = None;
+        let sending = self.send_with_redirects(message, &mut status);
+
+        #[cfg(feature = "tracing")]
+        let sending = tracing::Instrument::instrument(sending, span);
+
+        let result = sending.await;
+
+        if let Some((observer, origin, start)) = observing {
+            observer.notify(&origin, status, &result, start.elapsed());
+        }
+
+        result
+    }
+
+    async fn send_with_redirects(
+        &self,
+        mut message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        if let RedirectPolicy::Error = self.redirect_policy {
+            return self.send_with_retry(message, status).await;
+        }
+
+        let mut redirects = 0;
+
+        loop {
+            let location = match self.send_with_retry(message.clone(), status).await {
+                Err(WebPushError::Redirected(location)) => location,
+                result => return result,
+            };
 
-        trace!("Body: {:?}", body);
+            message = self.redirect_policy.redirect(message, &location, redirects)?;
+            redirects += 1;
 
-        trace!("Body text: {:?}", std::str::from_utf8(&body));
+            debug!(
+                "Following redirect {} to {}",
+                redirects,
+                super::origin(&message.endpoint)
+            );
+        }
+    }
+
+    async fn send_with_retry(
+        &self,
+        message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        let retry = match &self.retry {
+            Some(retry) => retry,
+            None => return self.send_message(message, status).await,
+        };
+
+        let mut retries = 0;
+
+        loop {
+            let error = match self.send_message(message.clone(), status).await {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+
+            match retry.delay(retries, &error) {
+                Some(delay) => {
+                    debug!("Retrying in {:?} after error: {}", delay, error);
+
+                    #[cfg(feature = "tracing")]
+                    tracing::debug!(retries, ?delay, %error, "Retrying");
+
+                    futures_timer::Delay::new(delay).await;
+                    retries += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    /// Sends the message once, keeping the status of the response in `status`.
+    async fn send_message(
+        &self,
+        message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        *status = None;
+
+        //Without the mock, there is only one transport.
+        #[allow(clippy::infallible_destructuring_match)]
+        let client = match &self.transport {
+            Transport::Isahc(client) => client,
+            #[cfg(any(test, feature = "test-util"))]
+            Transport::Mock(mock) => return mock.send(message, status),
+        };
+
+        let endpoint = message.endpoint.clone();
+        let mut request = request_builder::build_request::<isahc::AsyncBody>(message);
 
-        let response = request_builder::parse_response(response_status, body.to_vec());
+        let version_negotiation = match self.http_version {
+            HttpVersion::Negotiate => None,
+            HttpVersion::Http1 => Some(VersionNegotiation::http11()),
+            HttpVersion::Http2 => Some(VersionNegotiation::http2()),
+        };
 
-        trace!("Response: {:?}", response);
+        //Isahc keeps the request configuration in the extensions, but only lets it be set through a builder.
+        if let Some(version_negotiation) = version_negotiation {
+            let (config, _) = http::Request::builder()
+                .version_negotiation(version_negotiation)
+                .body(())
+                .unwrap()
+                .into_parts();
 
-        if let Err(WebPushError::ServerError(None)) = response {
-            Err(WebPushError::ServerError(retry_after))
-        } else {
-            Ok(response?)
+            request.extensions_mut().extend(config.extensions);
         }
+
+        //Only the origin is logged, the rest of the endpoint and the headers hold the push token and credentials.
+        debug!("Sending POST to {}", super::origin(&endpoint));
+
+        let requesting = client.send_async(request);
+
+        let response = requesting.await?;
+
+        let (parts, response_body) = response.into_parts();
+        //The push service answered, even if reading the body fails below.
+        *status = Some(parts.status);
+
+        let content_length: usize = parts
+            .headers
+            .get(CONTENT_LENGTH)
+            .and_then(|s| s.to_str().ok())
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(0)
+            .min(MAX_RESPONSE_SIZE);
+
+        let mut body: Vec<u8> = Vec::with_capacity(content_length);
+        //Only read as much of the body as is needed for diagnostics.
+        let mut chunks = response_body.take(MAX_RESPONSE_SIZE as u64);
+
+        chunks.read_to_end(&mut body).await.map_err(|e| match e.kind() {
+            ErrorKind::TimedOut => WebPushError::Timeout,
+            _ => WebPushError::InvalidResponse,
+        })?;
+
+        super::handle_response(&endpoint, parts.status, &parts.headers, body)
     }
 }
@@ -0,0 +1,459 @@
+//! An in-memory transport for testing code that sends notifications, without a push service.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use http::header::HeaderMap;
+use http::{Response, StatusCode, Uri};
+
+use crate::clients::request_builder::{self, WebPushResponse};
+use crate::error::WebPushError;
+use crate::message::WebPushMessage;
+
+/// A request the mock client would have sent to the push service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedRequest {
+    /// The endpoint the request was sent to.
+    pub endpoint: Uri,
+    /// All the headers of the request, including the encryption and VAPID headers.
+    pub headers: HeaderMap,
+    /// The encrypted body, empty for a message without a payload.
+    pub body: Vec<u8>,
+}
+
+/// Records the requests of a mock client, see `WebPushClient::mock`, and answers them with queued responses.
+///
+/// Responses go through the same handling as real ones, so for example a queued `410 Gone` response fails sending
+/// with [`WebPushError::EndpointNotValid`]. After the queue runs out, every request is answered with
+/// `201 Created`. The transport can be cloned to keep a handle on it after creating the client.
+///
+/// ```
+/// # use web_push::*;
+/// # use web_push::mock::MockTransport;
+/// # #[tokio::main]
+/// # async fn main() -> Result<(), WebPushError> {
+/// # let subscription_info = SubscriptionInfo::new(
+/// #     "https://updates.push.services.mozilla.com/wpush/v2/...",
+/// #     "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+/// #     "xS03Fi5ErfTNH_l9WHE9Ig",
+/// # );
+/// let transport = MockTransport::new();
+/// transport.push_status(http::StatusCode::GONE);
+///
+/// let client = WebPushClient::mock(transport.clone());
+/// let message = WebPushMessageBuilder::new(&subscription_info)?.build()?;
+///
+/// assert_eq!(Err(WebPushError::EndpointNotValid(None)), client.send(message).await);
+/// assert_eq!(1, transport.requests().len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockTransport {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    requests: Vec<RecordedRequest>,
+    responses: VecDeque<Result<Response<Vec<u8>>, WebPushError>>,
+}
+
+impl MockTransport {
+    /// Creates a transport with no responses queued.
+    pub fn new() -> MockTransport {
+        MockTransport::default()
+    }
+
+    /// Queues a response, with its status, headers and body.
+    pub fn push_response(&self, response: Response<Vec<u8>>) {
+        self.lock().responses.push_back(Ok(response));
+    }
+
+    /// Queues an empty response with the given status.
+    pub fn push_status(&self, status: StatusCode) {
+        let mut response = Response::new(Vec::new());
+        *response.status_mut() = status;
+
+        self.push_response(response);
+    }
+
+    /// Queues an error for a request that got no response, like [`WebPushError::Timeout`].
+    pub fn push_error(&self, error: WebPushError) {
+        self.lock().responses.push_back(Err(error));
+    }
+
+    /// The requests sent so far, in order.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.lock().requests.clone()
+    }
+
+    /// Forgets the requests sent so far.
+    pub fn clear_requests(&self) {
+        self.lock().requests.clear();
+    }
+
+    pub(crate) fn send(
+        &self,
+        message: WebPushMessage,
+        status: &mut Option<StatusCode>,
+    ) -> Result<WebPushResponse, WebPushError> {
+        let endpoint = message.endpoint.clone();
+        let (parts, body) = request_builder::build_request::<Vec<u8>>(message).into_parts();
+
+        let response = {
+            let mut state = self.lock();
+
+            state.requests.push(RecordedRequest {
+                endpoint: parts.uri,
+                headers: parts.headers,
+                body,
+            });
+
+            state.responses.pop_front()
+        };
+
+        match response {
+            Some(Ok(response)) => {
+                let (parts, body) = response.into_parts();
+                *status = Some(parts.status);
+                super::handle_response(&endpoint, parts.status, &parts.headers, body)
+            }
+            Some(Err(error)) => Err(error),
+            None => {
+                *status = Some(StatusCode::CREATED);
+
+                Ok(WebPushResponse {
+                    status: StatusCode::CREATED,
+                    location: None,
+                })
+            }
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MockState> {
+        //A panic while holding the lock can't leave the state inconsistent, so a poisoned lock is fine to use.
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use http::header::{LOCATION, RETRY_AFTER};
+    use http::{Response, StatusCode};
+
+    use crate::clients::mock::{MockTransport, RecordedRequest};
+    use crate::clients::{RedirectPolicy, RetryConfig, SendEvent};
+    use crate::error::{WebPushError, WebPushErrorKind};
+    use crate::fixtures::{subscription_info, AUTH};
+    use crate::http_ece::ContentEncoding;
+    use crate::message::{SubscriptionInfo, WebPushMessage, WebPushMessageBuilder};
+    use crate::vapid::builder::PartialVapidSignatureBuilder;
+    use crate::vapid::VapidSignatureBuilder;
+    use crate::WebPushClient;
+
+    fn message() -> WebPushMessage {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.build().unwrap()
+    }
+
+    fn signed_message() -> (WebPushMessage, PartialVapidSignatureBuilder) {
+        let pem = std::fs::File::open("resources/vapid_test_key.pem").unwrap();
+        let vapid_builder = VapidSignatureBuilder::from_pem_no_sub(pem).unwrap();
+
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_vapid_signature(vapid_builder.clone().add_sub_info(&info).build().unwrap());
+
+        (builder.build().unwrap(), vapid_builder)
+    }
+
+    fn redirect(location: &str) -> Response<Vec<u8>> {
+        let mut response = Response::new(Vec::new());
+        *response.status_mut() = StatusCode::PERMANENT_REDIRECT;
+        response.headers_mut().insert(LOCATION, location.parse().unwrap());
+
+        response
+    }
+
+    fn audience(request: &RecordedRequest) -> String {
+        let authorization = request.headers["Authorization"].to_str().unwrap();
+        let token = authorization.trim_start_matches("vapid t=").split(',').next().unwrap();
+        let claims = base64::decode_config(token.split('.').nth(1).unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+
+        serde_json::from_slice::<serde_json::Value>(&claims).unwrap()["aud"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn records_the_requests() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone());
+
+        let response = client.send(message()).await.unwrap();
+
+        assert_eq!(StatusCode::CREATED, response.status);
+
+        let requests = transport.requests();
+
+        assert_eq!(1, requests.len());
+        assert_eq!(
+            "https://updates.push.services.mozilla.com/wpush/v2/foo",
+            requests[0].endpoint.to_string()
+        );
+        assert_eq!("aes128gcm", requests[0].headers["Content-Encoding"]);
+        assert_eq!(230, requests[0].body.len());
+
+        transport.clear_requests();
+
+        assert!(transport.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_with_the_queued_responses() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone());
+
+        let mut created = Response::new(Vec::new());
+        *created.status_mut() = StatusCode::CREATED;
+        created
+            .headers_mut()
+            .insert(LOCATION, "https://push.example.com/m/123".parse().unwrap());
+
+        transport.push_response(created);
+        transport.push_status(StatusCode::GONE);
+        transport.push_error(WebPushError::Timeout);
+
+        assert_eq!(Some("123"), client.send(message()).await.unwrap().message_id());
+        assert_eq!(Err(WebPushError::EndpointNotValid(None)), client.send(message()).await);
+        assert_eq!(Err(WebPushError::Timeout), client.send(message()).await);
+        assert_eq!(StatusCode::CREATED, client.send(message()).await.unwrap().status);
+        assert_eq!(4, transport.requests().len());
+    }
+
+    #[test]
+    fn validates_without_sending() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone());
+
+        assert_eq!(Ok(()), client.validate(&message()));
+
+        let mut too_large = message();
+        too_large.payload.as_mut().unwrap().content = vec![0; 4097];
+
+        assert_eq!(Err(WebPushError::PayloadTooLarge), client.validate(&too_large));
+
+        let mut insecure = message();
+        insecure.endpoint = "ftp://updates.push.services.mozilla.com/wpush/v2/foo".parse().unwrap();
+
+        assert!(matches!(client.validate(&insecure), Err(WebPushError::InvalidUri(_))));
+        assert!(transport.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn sends_a_payload_to_all_subscriptions() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone());
+
+        let pem = std::fs::File::open("resources/vapid_test_key.pem").unwrap();
+        let vapid_builder = VapidSignatureBuilder::from_pem_no_sub(pem).unwrap();
+
+        let subscriptions = vec![
+            subscription_info(),
+            SubscriptionInfo::new("https://fcm.googleapis.com/fcm/send/bar", "not a key", AUTH),
+            SubscriptionInfo::new(
+                "https://fcm.googleapis.com/fcm/send/baz",
+                "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+                "EvcWjEgzr4rbvhfi3yds0A",
+            ),
+        ];
+
+        let results = client
+            .send_payload_to_all(&subscriptions, &vapid_builder, ContentEncoding::Aes128Gcm, b"test", 2)
+            .await;
+
+        assert_eq!(3, results.len());
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(WebPushError::InvalidCryptoKeys(_))));
+        assert!(results[2].is_ok());
+
+        let requests = transport.requests();
+
+        assert_eq!(2, requests.len());
+        assert_ne!(requests[0].body, requests[1].body);
+        assert!(requests.iter().all(|request| request.headers["Authorization"]
+            .to_str()
+            .unwrap()
+            .starts_with("vapid t=")));
+    }
+
+    #[tokio::test]
+    async fn retries_with_the_mock() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone()).with_retry(RetryConfig {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        });
+
+        let mut unavailable = Response::new(Vec::new());
+        *unavailable.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        unavailable.headers_mut().insert(RETRY_AFTER, "0".parse().unwrap());
+
+        transport.push_response(unavailable);
+        transport.push_status(StatusCode::TOO_MANY_REQUESTS);
+
+        let info = subscription_info();
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_message_key("order-42");
+
+        assert!(client.send(builder.build().unwrap()).await.is_ok());
+
+        let requests = transport.requests();
+
+        assert_eq!(3, requests.len());
+        //Every attempt sends the same message, so a message key stays the same.
+        assert!(requests
+            .iter()
+            .all(|request| request.headers["Idempotency-Key"] == "order-42"));
+    }
+
+    #[tokio::test]
+    async fn observes_every_send() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone())
+            .with_retry(RetryConfig {
+                max_retries: 1,
+                base_delay: Duration::from_millis(1),
+                max_delay: Duration::from_millis(10),
+            })
+            .with_observer(move |event: &SendEvent<'_>| {
+                let kind = event.error.map(WebPushError::kind);
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((event.origin.to_string(), event.status, kind));
+            });
+
+        transport.push_status(StatusCode::SERVICE_UNAVAILABLE);
+        transport.push_error(WebPushError::Timeout);
+        transport.push_status(StatusCode::GONE);
+
+        assert!(client.send(message()).await.is_err());
+        assert!(client.send(message()).await.is_err());
+        assert!(client.send(message()).await.is_ok());
+
+        let origin = String::from("https://updates.push.services.mozilla.com");
+
+        //Retries are part of one send, so only the last attempt is reported.
+        assert_eq!(
+            vec![
+                (origin.clone(), None, Some(WebPushErrorKind::Timeout)),
+                (
+                    origin.clone(),
+                    Some(StatusCode::GONE),
+                    Some(WebPushErrorKind::EndpointNotValid)
+                ),
+                (origin, Some(StatusCode::CREATED), None),
+            ],
+            *events.lock().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_on_redirects_by_default() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone());
+
+        transport.push_response(redirect("/wpush/v2/bar"));
+
+        assert_eq!(
+            Err(WebPushError::Redirected(String::from(
+                "https://updates.push.services.mozilla.com/wpush/v2/bar"
+            ))),
+            client.send(message()).await
+        );
+        assert_eq!(1, transport.requests().len());
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_signing_again_for_another_origin() {
+        let (message, vapid_builder) = signed_message();
+
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone()).with_redirect_policy(RedirectPolicy::Follow {
+            max_redirects: 2,
+            vapid_builder: Some(vapid_builder),
+        });
+
+        transport.push_response(redirect("/wpush/v2/bar"));
+        transport.push_response(redirect("https://fcm.googleapis.com/fcm/send/baz"));
+
+        assert!(client.send(message).await.is_ok());
+
+        let requests = transport.requests();
+        let endpoints: Vec<String> = requests.iter().map(|request| request.endpoint.to_string()).collect();
+
+        assert_eq!(
+            vec![
+                "https://updates.push.services.mozilla.com/wpush/v2/foo",
+                "https://updates.push.services.mozilla.com/wpush/v2/bar",
+                "https://fcm.googleapis.com/fcm/send/baz",
+            ],
+            endpoints
+        );
+
+        //The token is only valid for one origin, so only the redirect to another origin needs a new one.
+        assert_eq!(
+            requests[0].headers["Authorization"],
+            requests[1].headers["Authorization"]
+        );
+        assert_eq!("https://updates.push.services.mozilla.com", audience(&requests[1]));
+        assert_eq!("https://fcm.googleapis.com", audience(&requests[2]));
+    }
+
+    #[tokio::test]
+    async fn stops_following_redirects() {
+        let transport = MockTransport::new();
+        let client = WebPushClient::mock(transport.clone()).with_redirect_policy(RedirectPolicy::Follow {
+            max_redirects: 1,
+            vapid_builder: None,
+        });
+
+        transport.push_response(redirect("/wpush/v2/bar"));
+        transport.push_response(redirect("/wpush/v2/baz"));
+
+        assert_eq!(
+            Err(WebPushError::Redirected(String::from(
+                "https://updates.push.services.mozilla.com/wpush/v2/baz"
+            ))),
+            client.send(message()).await
+        );
+        assert_eq!(2, transport.requests().len());
+        transport.clear_requests();
+
+        //Without a key, a signed message can't be sent to another origin.
+        transport.push_response(redirect("https://fcm.googleapis.com/fcm/send/baz"));
+
+        assert_eq!(
+            Err(WebPushError::Redirected(String::from(
+                "https://fcm.googleapis.com/fcm/send/baz"
+            ))),
+            client.send(signed_message().0).await
+        );
+        assert_eq!(1, transport.requests().len());
+    }
+}
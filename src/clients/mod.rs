@@ -3,10 +3,574 @@
 //! [`request_builder`] contains the functions used to send and consume push http messages.
 //! This module should be consumed by each client, by using [`http`]'s flexible api.
 
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http::header::{HeaderMap, LOCATION, RETRY_AFTER};
+use http::{StatusCode, Uri};
+
+use crate::clients::request_builder::WebPushResponse;
+use crate::error::{RetryAfter, WebPushError};
+use crate::http_ece::ContentEncoding;
+use crate::message::{parse_endpoint, SubscriptionInfo, WebPushMessage, WebPushMessageBuilder};
+use crate::vapid::builder::PartialVapidSignatureBuilder;
+
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
 pub mod request_builder;
 
+/// The most bytes of a response body the clients will read, to avoid unbounded memory use on a
+/// misbehaving push service.
+pub(crate) const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// How long a request may take before the clients give up on it, unless set otherwise.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a client retries sending a message after a transient failure, see `WebPushClient::with_retry`.
+///
+/// Only timeouts, connection failures and server errors, including rate limiting, are retried. Other errors, like an
+/// expired subscription or a bad TLS certificate, fail the same way on every attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryConfig {
+    /// How many times to retry after the first attempt.
+    pub max_retries: u32,
+    /// The delay before the first retry. The delay doubles for each retry after that.
+    pub base_delay: Duration,
+    /// The longest delay between two attempts. If the push service asks to wait longer than this with a
+    /// `Retry-After` header, the error is returned instead of retrying.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Gets how long to wait before retrying after `error`, or `None` if it shouldn't be retried. `retry` is the
+    /// number of retries done so far.
+    pub(crate) fn delay(&self, retry: u32, error: &WebPushError) -> Option<Duration> {
+        if retry >= self.max_retries {
+            return None;
+        }
+
+        let backoff = self
+            .base_delay
+            .checked_mul(2u32.saturating_pow(retry))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+
+        match error {
+            WebPushError::Timeout | WebPushError::ConnectionError(_) | WebPushError::ServerError(None) => Some(backoff),
+            WebPushError::ServerError(Some(retry_after)) if *retry_after <= self.max_delay => {
+                Some(backoff.max(*retry_after))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// What a client does when the push service answers with a redirect, see `WebPushClient::with_redirect_policy`.
+///
+/// Push services rarely redirect, but when one does the subscription has usually moved for good, so the stored
+/// endpoint is best updated. Redirects are not followed by default.
+#[derive(Clone, Default)]
+pub enum RedirectPolicy {
+    /// Fails with [`WebPushError::Redirected`], which has the new endpoint to store. This is the default.
+    #[default]
+    Error,
+    /// Sends the message again to the new endpoint, failing with [`WebPushError::Redirected`] once more than
+    /// `max_redirects` redirects were followed.
+    ///
+    /// The `aud` claim of the VAPID token is the origin of the endpoint, so a signed message redirected to another
+    /// origin has to be signed again, which is done with `vapid_builder`. It must hold the key the message was signed
+    /// with, see [`PartialVapidSignatureBuilder::resign`]. Without it, such redirects fail with
+    /// [`WebPushError::Redirected`] instead of sending a token the new push service would reject.
+    Follow {
+        /// How many redirects to follow for one message.
+        max_redirects: u32,
+        /// The key for signing messages again after a redirect to another origin.
+        vapid_builder: Option<PartialVapidSignatureBuilder>,
+    },
+}
+
+impl fmt::Debug for RedirectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedirectPolicy::Error => f.write_str("Error"),
+            //The builder holds a private key, so only whether there is one is shown.
+            RedirectPolicy::Follow {
+                max_redirects,
+                vapid_builder,
+            } => f
+                .debug_struct("Follow")
+                .field("max_redirects", max_redirects)
+                .field("vapid_builder", &vapid_builder.is_some())
+                .finish(),
+        }
+    }
+}
+
+impl RedirectPolicy {
+    /// Gets the message to send to `location`, or the error to fail with. `redirects` is the number of redirects
+    /// followed so far.
+    pub(crate) fn redirect(
+        &self,
+        mut message: WebPushMessage,
+        location: &str,
+        redirects: u32,
+    ) -> Result<WebPushMessage, WebPushError> {
+        let vapid_builder = match self {
+            RedirectPolicy::Follow {
+                max_redirects,
+                vapid_builder,
+            } if redirects < *max_redirects => vapid_builder,
+            _ => return Err(WebPushError::Redirected(location.to_string())),
+        };
+
+        let endpoint = parse_endpoint(location)?;
+
+        if origin(&endpoint) != origin(&message.endpoint) {
+            if let Some(signature) = &message.vapid_signature {
+                let vapid_builder = vapid_builder
+                    .as_ref()
+                    .ok_or_else(|| WebPushError::Redirected(location.to_string()))?;
+
+                message.vapid_signature = Some(vapid_builder.resign(signature, &endpoint)?);
+            }
+        }
+
+        message.endpoint = endpoint;
+
+        Ok(message)
+    }
+}
+
+/// What happened when a client sent a message, given to a [`SendObserver`].
+#[derive(Debug, Clone, Copy)]
+pub struct SendEvent<'a> {
+    /// The origin of the endpoint, like `https://fcm.googleapis.com`. The rest of the endpoint is a secret push
+    /// token, so it is left out.
+    pub origin: &'a str,
+    /// The status of the last response, or `None` if the push service never answered, like after a timeout.
+    pub status: Option<StatusCode>,
+    /// The error sending failed with, or `None` if the message was delivered.
+    pub error: Option<&'a WebPushError>,
+    /// How long sending took, including the retries and the delays between them.
+    pub duration: Duration,
+}
+
+/// Gets told about every message a client sends, for example to count deliveries and failures in a metrics
+/// system, see `WebPushClient::with_observer`.
+///
+/// It's called once per message after the last attempt, so retries are included in the event instead of being
+/// reported separately. As it's called while sending, it should be quick. Closures taking a [`SendEvent`]
+/// are observers too.
+pub trait SendObserver: Send + Sync {
+    /// Called after sending a message, whether it was delivered or not.
+    fn on_send(&self, event: &SendEvent<'_>);
+}
+
+impl<F> SendObserver for F
+where
+    F: Fn(&SendEvent<'_>) + Send + Sync,
+{
+    fn on_send(&self, event: &SendEvent<'_>) {
+        self(event)
+    }
+}
+
+/// The observer of a client, shared between clones of its builder.
+#[derive(Clone)]
+pub(crate) struct Observer(Arc<dyn SendObserver>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+impl Observer {
+    pub(crate) fn new<O: SendObserver + 'static>(observer: O) -> Observer {
+        Observer(Arc::new(observer))
+    }
+
+    /// Tells the observer how sending to `origin` went.
+    pub(crate) fn notify(
+        &self,
+        origin: &str,
+        status: Option<StatusCode>,
+        result: &Result<WebPushResponse, WebPushError>,
+        duration: Duration,
+    ) {
+        self.0.on_send(&SendEvent {
+            origin,
+            status,
+            error: result.as_ref().err(),
+            duration,
+        });
+    }
+}
+
+/// Signs and encrypts `payload` for one of the subscriptions of `WebPushClient::send_payload_to_all`.
+pub(crate) fn build_for_subscription(
+    subscription_info: &SubscriptionInfo,
+    vapid_builder: &PartialVapidSignatureBuilder,
+    encoding: ContentEncoding,
+    payload: &[u8],
+) -> Result<WebPushMessage, WebPushError> {
+    let vapid_signature = vapid_builder.clone().add_sub_info(subscription_info).build()?;
+
+    let mut builder = WebPushMessageBuilder::new(subscription_info)?;
+    builder.set_payload(encoding, payload);
+    builder.set_vapid_signature(vapid_signature);
+
+    builder.build()
+}
+
+/// Adds the default headers of a client to the message, except for headers the message already has.
+pub(crate) fn add_default_headers(mut message: WebPushMessage, default_headers: &HeaderMap) -> WebPushMessage {
+    for name in default_headers.keys() {
+        if !message.extra_headers.contains_key(name) {
+            for value in default_headers.get_all(name) {
+                message.extra_headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+
+    message
+}
+
+/// Checks the message with the default headers added, and assembles its request without sending it.
+pub(crate) fn validate(message: &WebPushMessage, default_headers: &HeaderMap) -> Result<(), WebPushError> {
+    let message = add_default_headers(message.clone(), default_headers);
+
+    message.validate()?;
+    request_builder::build_request::<Vec<u8>>(message);
+
+    Ok(())
+}
+
+/// Turns the response of the push service to a message sent to `endpoint` into the result of sending. Server errors
+/// and rate limiting keep the `Retry-After` duration, if given, and redirects the new endpoint.
+pub(crate) fn handle_response(
+    endpoint: &Uri,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+) -> Result<WebPushResponse, WebPushError> {
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|ra| ra.to_str().ok())
+        .and_then(RetryAfter::from_str);
+
+    trace!("Response status: {}", status);
+
+    #[cfg(feature = "tracing")]
+    tracing::debug!(status = status.as_u16(), retry_after = ?retry_after, "Push service responded");
+
+    //Redirects without a usable location are unexpected responses like any other.
+    if status.is_redirection() {
+        let location = headers
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| resolve_location(endpoint, location));
+
+        if let Some(location) = location {
+            #[cfg(feature = "tracing")]
+            if let Ok(uri) = location.parse() {
+                tracing::debug!(origin = %origin(&uri), "Push service redirected the message");
+            }
+
+            return Err(WebPushError::Redirected(location));
+        }
+    }
+
+    let response = request_builder::parse_response(status, body);
+
+    #[cfg(feature = "tracing")]
+    if let Err(error) = &response {
+        tracing::debug!(%error, "Push service rejected the message");
+    }
+
+    match response {
+        Ok(()) => Ok(WebPushResponse::new(status, headers)),
+        Err(WebPushError::ServerError(None)) => Err(WebPushError::ServerError(retry_after)),
+        Err(error) => Err(error),
+    }
+}
+
+/// Resolves the `Location` of a redirect against the endpoint redirected from. Only absolute URLs and absolute paths
+/// are supported.
+fn resolve_location(endpoint: &Uri, location: &str) -> Option<String> {
+    let location: Uri = location.parse().ok()?;
+
+    if location.scheme().is_some() {
+        return Some(location.to_string());
+    }
+
+    let path = location
+        .path_and_query()
+        .filter(|path| path.as_str().starts_with('/'))?;
+
+    let mut parts = endpoint.clone().into_parts();
+    parts.path_and_query = Some(path.clone());
+
+    Uri::from_parts(parts).ok().map(|uri| uri.to_string())
+}
+
+/// Gets the origin of an endpoint, the part of it that is safe to log.
+pub(crate) fn origin(endpoint: &Uri) -> String {
+    match (endpoint.scheme_str(), endpoint.host()) {
+        (Some(scheme), Some(host)) => match endpoint.port_u16() {
+            Some(port) => format!("{}://{}:{}", scheme, host, port),
+            None => format!("{}://{}", scheme, host),
+        },
+        _ => String::from("unknown"),
+    }
+}
+
+/// Creates the span for sending a message. Only the origin of the endpoint is recorded, as the rest of it is a
+/// secret push token, and the keys and the VAPID token are left out for the same reason.
+#[cfg(feature = "tracing")]
+pub(crate) fn send_span(message: &WebPushMessage) -> tracing::Span {
+    tracing::debug_span!(
+        "web_push_send",
+        origin = %origin(&message.endpoint),
+        content_encoding = message.payload.as_ref().map(|p| p.content_encoding).unwrap_or("none"),
+        payload_size = message.body().len(),
+    )
+}
+
 #[cfg(feature = "hyper-client")]
 pub mod hyper_client;
 
-#[cfg(not(feature = "hyper-client"))]
+#[cfg(all(feature = "isahc", not(feature = "hyper-client")))]
 pub mod isahc_client;
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::header::{HeaderMap, LOCATION};
+    use http::{StatusCode, Uri};
+
+    use crate::clients::{add_default_headers, handle_response, RetryConfig};
+    use crate::error::{ErrorSource, WebPushError};
+    use crate::fixtures::{subscription_info, AUTH, P256DH};
+    use crate::message::{SubscriptionInfo, WebPushMessageBuilder};
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+        }
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let config = config();
+
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            config.delay(0, &WebPushError::Timeout)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(200)),
+            config.delay(1, &WebPushError::Timeout)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(300)),
+            config.delay(2, &WebPushError::ServerError(None))
+        );
+        assert_eq!(None, config.delay(3, &WebPushError::Timeout));
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            config.delay(
+                0,
+                &WebPushError::ConnectionError(ErrorSource::from("connection refused"))
+            )
+        );
+    }
+
+    #[test]
+    fn respects_retry_after() {
+        let config = config();
+
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            config.delay(0, &WebPushError::ServerError(Some(Duration::from_millis(250))))
+        );
+        assert_eq!(
+            None,
+            config.delay(0, &WebPushError::ServerError(Some(Duration::from_secs(1))))
+        );
+    }
+
+    #[test]
+    fn adds_default_headers() {
+        let info = subscription_info();
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.add_header("X-Api-Key", "own");
+
+        let mut defaults = HeaderMap::new();
+        defaults.insert("x-api-key", "default".parse().unwrap());
+        defaults.insert("x-tenant", "acme".parse().unwrap());
+
+        let message = add_default_headers(builder.build().unwrap(), &defaults);
+
+        assert_eq!("own", message.extra_headers["X-Api-Key"]);
+        assert_eq!("acme", message.extra_headers["X-Tenant"]);
+        assert_eq!(2, message.extra_headers.len());
+    }
+
+    #[cfg(any(feature = "isahc", feature = "hyper-client"))]
+    #[test]
+    fn client_builder_rejects_protocol_headers() {
+        let result = crate::WebPushClient::builder().with_header("TTL", "0").build();
+
+        assert_eq!(Some(WebPushError::InvalidHeader(String::from("TTL"))), result.err());
+    }
+
+    #[cfg(feature = "hyper-client")]
+    #[test]
+    fn hyper_builder_keeps_options_with_a_custom_client() {
+        let client = hyper::Client::builder().build::<_, hyper::Body>(hyper_tls::HttpsConnector::new());
+
+        let result = crate::WebPushClient::builder()
+            .with_timeout(Duration::from_secs(1))
+            .with_header("TTL", "0")
+            .with_client(client.clone())
+            .build();
+        assert_eq!(Some(WebPushError::InvalidHeader(String::from("TTL"))), result.err());
+
+        let result = crate::WebPushClient::builder()
+            .with_client(client)
+            .with_header("X-Api-Key", "secret")
+            .build();
+        assert!(result.is_ok());
+    }
+
+    #[cfg(any(feature = "isahc", feature = "hyper-client"))]
+    #[tokio::test]
+    async fn dropping_send_closes_the_connection() {
+        //The isahc client notices a dropped send on the next activity or the client timeout, hyper right away.
+        use std::io::{ErrorKind, Read};
+        use std::net::TcpListener;
+        use std::sync::mpsc;
+        use std::time::Instant;
+
+        const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+        //The server waits well past the client timeout, which closes the connection at the latest.
+        const SERVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (closed_sender, closed) = mpsc::channel();
+
+        //A push service that reads the request but never answers, until the client closes the connection. It gives
+        //up instead of blocking if the client never connects, so a broken client fails the test instead of hanging.
+        std::thread::spawn(move || {
+            listener.set_nonblocking(true).unwrap();
+            let deadline = Instant::now() + SERVER_TIMEOUT;
+
+            let mut stream = loop {
+                match listener.accept() {
+                    Ok((stream, _)) => break stream,
+                    Err(e) if e.kind() == ErrorKind::WouldBlock && Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Err(_) => return closed_sender.send(Err("the client never connected")),
+                }
+            };
+
+            stream.set_nonblocking(false).unwrap();
+            stream.set_read_timeout(Some(SERVER_TIMEOUT)).unwrap();
+
+            let mut buffer = [0; 1024];
+            let result = loop {
+                match stream.read(&mut buffer) {
+                    Ok(0) => break Ok(()),
+                    Ok(_) => continue,
+                    Err(_) => break Err("the connection was not closed"),
+                }
+            };
+
+            closed_sender.send(result)
+        });
+
+        //Without the feature, the send is dropped during the TLS handshake instead of while waiting for a response.
+        let scheme = if cfg!(feature = "allow-http-localhost") {
+            "http"
+        } else {
+            "https"
+        };
+        let info = SubscriptionInfo::new(format!("{}://127.0.0.1:{}/push", scheme, port).as_str(), P256DH, AUTH);
+        let message = WebPushMessageBuilder::new(&info).unwrap().build().unwrap();
+        let client = crate::WebPushClient::with_timeout(CLIENT_TIMEOUT).unwrap();
+
+        let send = tokio::time::timeout(Duration::from_millis(200), client.send(message));
+
+        assert!(send.await.is_err(), "the push service never answers");
+
+        //Waiting on a blocking thread, so the runtime can still drive the connection of the hyper client.
+        let closed = tokio::task::spawn_blocking(move || closed.recv_timeout(SERVER_TIMEOUT * 3))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            Ok(Ok(())),
+            closed,
+            "the connection should be closed when the send is dropped"
+        );
+    }
+
+    #[test]
+    fn does_not_retry_permanent_errors() {
+        let config = config();
+
+        assert_eq!(None, config.delay(0, &WebPushError::BadRequest(None)));
+        assert_eq!(None, config.delay(0, &WebPushError::Unauthorized(None)));
+        assert_eq!(None, config.delay(0, &WebPushError::EndpointNotValid(None)));
+        assert_eq!(
+            None,
+            config.delay(0, &WebPushError::TlsError(ErrorSource::from("bad certificate")))
+        );
+    }
+
+    #[test]
+    fn resolves_redirect_locations() {
+        let endpoint: Uri = "https://updates.push.services.mozilla.com/wpush/v2/foo"
+            .parse()
+            .unwrap();
+
+        let redirect = |location: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(LOCATION, location.parse().unwrap());
+
+            handle_response(&endpoint, StatusCode::MOVED_PERMANENTLY, &headers, Vec::new())
+        };
+
+        assert_eq!(
+            Err(WebPushError::Redirected(String::from(
+                "https://fcm.googleapis.com/fcm/send/bar"
+            ))),
+            redirect("https://fcm.googleapis.com/fcm/send/bar")
+        );
+        assert_eq!(
+            Err(WebPushError::Redirected(String::from(
+                "https://updates.push.services.mozilla.com/wpush/v2/bar?x=1"
+            ))),
+            redirect("/wpush/v2/bar?x=1")
+        );
+        //Without a usable location, a redirect is an unexpected response.
+        assert!(matches!(redirect("bar"), Err(WebPushError::Other(_))));
+    }
+}
@@ -1,7 +1,7 @@
 //! Functions used to send and consume push http messages.
 //! This module can be used to build custom clients.
 
-use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
+use http::header::{HeaderMap, LOCATION};
 use http::{Request, StatusCode};
 
 use crate::{error::WebPushError, message::WebPushMessage};
@@ -14,6 +14,43 @@ struct ErrorInfo {
     message: String,
 }
 
+/// The response of the push service to a message it accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebPushResponse {
+    /// The status of the response, usually `201 Created`. Any `2xx` status is a success.
+    pub status: StatusCode,
+    /// The `Location` header, the URL of the message on the push service, if given.
+    pub location: Option<String>,
+}
+
+impl WebPushResponse {
+    /// Creates the response from the status and headers sent by the push service.
+    pub fn new(status: StatusCode, headers: &HeaderMap) -> WebPushResponse {
+        WebPushResponse {
+            status,
+            location: headers
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .map(String::from),
+        }
+    }
+
+    /// Whether the push service answered `202 Accepted`, acknowledging the message before storing it. This is
+    /// the answer to [`WebPushMessageBuilder::set_respond_async`](crate::WebPushMessageBuilder::set_respond_async).
+    pub fn is_async(&self) -> bool {
+        self.status == StatusCode::ACCEPTED
+    }
+
+    /// The ID the push service gave to the message, the last segment of the `Location` URL. Useful for
+    /// correlating a message with the logs of the push service, or in support requests.
+    pub fn message_id(&self) -> Option<&str> {
+        self.location
+            .as_deref()
+            .and_then(|location| location.trim_end_matches('/').rsplit('/').next())
+            .filter(|id| !id.is_empty())
+    }
+}
+
 /// Builds the request to send to the push service.
 ///
 /// This function is generic over the request body, this means that you can swap out client implementations
@@ -25,7 +62,7 @@ struct ErrorInfo {
 /// # use web_push::{SubscriptionInfo, WebPushMessageBuilder};
 /// # use web_push::request_builder::build_request;
 /// let info = SubscriptionInfo::new(
-///  "http://google.com",
+///  "https://google.com",
 ///  "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
 ///  "xS03Fi5ErfTNH_l9WHE9Ig",
 ///  );
@@ -40,22 +77,14 @@ pub fn build_request<T>(message: WebPushMessage) -> Request<T>
 where
     T: From<Vec<u8>> + From<&'static str>, //This bound can be reduced to a &[u8] instead of str if needed
 {
-    let mut builder = Request::builder()
-        .method("POST")
-        .uri(message.endpoint)
-        .header("TTL", format!("{}", message.ttl).as_bytes());
+    let headers = message.headers();
+    let mut builder = Request::builder().method("POST").uri(message.endpoint);
 
-    if let Some(payload) = message.payload {
-        builder = builder
-            .header(CONTENT_ENCODING, payload.content_encoding)
-            .header(CONTENT_LENGTH, format!("{}", payload.content.len() as u64).as_bytes())
-            .header(CONTENT_TYPE, "application/octet-stream");
-
-        for (k, v) in payload.crypto_headers.into_iter() {
-            let v: &str = v.as_ref();
-            builder = builder.header(k, v);
-        }
+    if let Some(builder_headers) = builder.headers_mut() {
+        *builder_headers = headers;
+    }
 
+    if let Some(payload) = message.payload {
         builder.body(payload.content.into()).unwrap()
     } else {
         builder.body("".into()).unwrap()
@@ -63,26 +92,49 @@ where
 }
 
 /// Parses the response from the push service, and will return `Err` if the request was bad.
+///
+/// Push services usually explain why a request was rejected in the body, so the body text is kept in the error.
 pub fn parse_response(response_status: StatusCode, body: Vec<u8>) -> Result<(), WebPushError> {
     match response_status {
         status if status.is_success() => Ok(()),
         status if status.is_server_error() => Err(WebPushError::ServerError(None)),
+        //Rate limiting is retryable as well, the clients attach the `Retry-After` value.
+        StatusCode::TOO_MANY_REQUESTS => Err(WebPushError::ServerError(None)),
+
+        StatusCode::UNAUTHORIZED => Err(WebPushError::Unauthorized(body_text(&body))),
+        StatusCode::FORBIDDEN => Err(WebPushError::Forbidden(body_text(&body))),
+        StatusCode::GONE => Err(WebPushError::EndpointNotValid(body_text(&body))),
+        StatusCode::NOT_FOUND => Err(WebPushError::EndpointNotFound(body_text(&body))),
+        StatusCode::PAYLOAD_TOO_LARGE => {
+            //The same error as when the payload is too large to send, but the body may tell the actual limit.
+            if let Some(text) = body_text(&body) {
+                debug!("Push service rejected the payload as too large: {}", text);
+            }
+
+            Err(WebPushError::PayloadTooLarge)
+        }
 
-        StatusCode::UNAUTHORIZED => Err(WebPushError::Unauthorized),
-        StatusCode::GONE => Err(WebPushError::EndpointNotValid),
-        StatusCode::NOT_FOUND => Err(WebPushError::EndpointNotFound),
-        StatusCode::PAYLOAD_TOO_LARGE => Err(WebPushError::PayloadTooLarge),
-
-        StatusCode::BAD_REQUEST => match String::from_utf8(body) {
-            Err(_) => Err(WebPushError::BadRequest(None)),
-            Ok(body_str) => match serde_json::from_str::<ErrorInfo>(&body_str) {
-                Ok(error_info) => Err(WebPushError::BadRequest(Some(error_info.error))),
-                Err(_) if body_str.is_empty() => Err(WebPushError::BadRequest(None)),
-                Err(_) => Err(WebPushError::BadRequest(None)),
-            },
+        StatusCode::BAD_REQUEST => match serde_json::from_slice::<ErrorInfo>(&body) {
+            Ok(error_info) => Err(WebPushError::BadRequest(Some(error_info.error))),
+            Err(_) => Err(WebPushError::BadRequest(body_text(&body))),
         },
 
-        e => Err(WebPushError::Other(format!("{:?}", e))),
+        e => match body_text(&body) {
+            Some(text) => Err(WebPushError::Other(format!("{:?}: {}", e, text))),
+            None => Err(WebPushError::Other(format!("{:?}", e))),
+        },
+    }
+}
+
+/// Gets the response body as text, or `None` if empty.
+fn body_text(body: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(body);
+    let text = text.trim();
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
     }
 }
 
@@ -93,7 +145,9 @@ mod tests {
     use crate::clients::request_builder::*;
     use crate::error::WebPushError;
     use crate::http_ece::ContentEncoding;
-    use crate::message::WebPushMessageBuilder;
+    use crate::message::{SubscriptionInfo, Urgency, WebPushMessageBuilder};
+    use crate::vapid::VapidSignature;
+    use regex::Regex;
 
     #[test]
     fn builds_a_correct_request_with_empty_payload() {
@@ -119,6 +173,99 @@ mod tests {
         assert_eq!(expected_uri.host(), request.uri().host());
     }
 
+    #[test]
+    fn builds_a_correct_request_for_ipv6_and_custom_ports() {
+        for endpoint in [
+            "https://[::1]:8443/push/foo",
+            "https://[2001:db8::1]/push/foo",
+            "https://push.example.com:8443/push/foo",
+        ] {
+            let info = SubscriptionInfo::new(
+                endpoint,
+                "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+                "EvcWjEgzr4rbvhfi3yds0A",
+            );
+
+            let request = build_request::<isahc::Body>(WebPushMessageBuilder::new(&info).unwrap().build().unwrap());
+            let expected: Uri = endpoint.parse().unwrap();
+
+            assert_eq!(expected.authority(), request.uri().authority());
+            assert_eq!("/push/foo", request.uri().path());
+            assert_eq!(endpoint, request.uri().to_string());
+        }
+    }
+
+    #[test]
+    fn builds_a_correct_request_with_zero_ttl() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/foo",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+
+        builder.set_ttl(0);
+
+        let request = build_request::<isahc::Body>(builder.build().unwrap());
+        let ttl = request.headers().get("TTL").unwrap().to_str().unwrap();
+
+        assert_eq!("0", ttl);
+    }
+
+    #[test]
+    fn builds_a_correct_request_with_urgency() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/foo",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        let request = build_request::<isahc::Body>(WebPushMessageBuilder::new(&info).unwrap().build().unwrap());
+        assert!(request.headers().get("Urgency").is_none());
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_urgency(Urgency::VeryLow);
+
+        let request = build_request::<isahc::Body>(builder.build().unwrap());
+        let urgency = request.headers().get("Urgency").unwrap().to_str().unwrap();
+
+        assert_eq!("very-low", urgency);
+    }
+
+    #[test]
+    fn builds_a_correct_request_with_topic() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/foo",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_topic("live-score_42".to_string());
+
+        let request = build_request::<isahc::Body>(builder.build().unwrap());
+        let topic = request.headers().get("Topic").unwrap().to_str().unwrap();
+
+        assert_eq!("live-score_42", topic);
+    }
+
+    #[test]
+    fn rejects_an_invalid_topic() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/foo",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        for topic in ["", "has spaces", "slash/", &"a".repeat(33)] {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+            builder.set_topic(topic.to_string());
+
+            assert_eq!(Some(WebPushError::InvalidTopic), builder.build().err());
+        }
+    }
+
     #[test]
     fn builds_a_correct_request_with_payload() {
         //This *was* a real token
@@ -145,6 +292,60 @@ mod tests {
         assert_eq!("230", length);
         assert_eq!("aes128gcm", encoding);
         assert_eq!(expected_uri.host(), request.uri().host());
+        assert!(request.headers().get("Encryption").is_none());
+        assert!(request.headers().get("Crypto-Key").is_none());
+    }
+
+    #[test]
+    fn builds_a_correct_request_with_aesgcm_payload() {
+        let sub = serde_json::json!({"endpoint":"https://fcm.googleapis.com/fcm/send/eKClHsXFm9E:APA91bH2x3gNOMv4dF1lQfCgIfOet8EngqKCAUS5DncLOd5hzfSUxcjigIjw9ws-bqa-KmohqiTOcgepAIVO03N39dQfkEkopubML_m3fyvF03pV9_JCB7SxpUjcFmBSVhCaWS6m8l7x",
+            "expirationTime":null,
+            "keys":{"p256dh":
+                "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+                "auth":"EvcWjEgzr4rbvhfi3yds0A"}
+        });
+
+        let info = serde_json::from_value(sub).unwrap();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+
+        builder.set_payload(ContentEncoding::AesGcm, "test".as_bytes());
+
+        let request = build_request::<isahc::Body>(builder.build().unwrap());
+
+        let encoding = request.headers().get("Content-Encoding").unwrap().to_str().unwrap();
+
+        assert_eq!("aesgcm", encoding);
+        assert!(request.headers().get("Encryption").is_some());
+        assert!(request.headers().get("Crypto-Key").is_some());
+    }
+
+    #[test]
+    fn builds_a_correct_request_with_vapid_signature() {
+        let info = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/foo",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        let auth_re = Regex::new(r"vapid t=(?P<sig_t>[^,]*), k=(?P<sig_k>[^,]*)").unwrap();
+
+        for payload in [None, Some(ContentEncoding::Aes128Gcm), Some(ContentEncoding::AesGcm)] {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+            builder.set_vapid_signature(VapidSignature {
+                auth_t: String::from("foo"),
+                auth_k: String::from("bar").into_bytes(),
+            });
+
+            if let Some(encoding) = payload {
+                builder.set_payload(encoding, "test".as_bytes());
+            }
+
+            let request = build_request::<isahc::Body>(builder.build().unwrap());
+            let authorization = request.headers().get("Authorization").unwrap().to_str().unwrap();
+
+            assert!(auth_re.captures(authorization).is_some());
+        }
     }
 
     #[test]
@@ -152,10 +353,45 @@ mod tests {
         assert_eq!(Ok(()), parse_response(StatusCode::OK, vec![]))
     }
 
+    #[test]
+    fn parses_any_2xx_response_as_success() {
+        for status in [StatusCode::CREATED, StatusCode::ACCEPTED, StatusCode::NO_CONTENT] {
+            assert_eq!(Ok(()), parse_response(status, b"queued".to_vec()))
+        }
+
+        assert!(WebPushResponse::new(StatusCode::ACCEPTED, &HeaderMap::new()).is_async());
+        assert!(!WebPushResponse::new(StatusCode::CREATED, &HeaderMap::new()).is_async());
+    }
+
+    #[test]
+    fn reads_the_location_of_a_successful_response() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            LOCATION,
+            "https://updates.push.services.mozilla.com/m/gAAAAABfoo"
+                .parse()
+                .unwrap(),
+        );
+
+        let response = WebPushResponse::new(StatusCode::CREATED, &headers);
+
+        assert_eq!(StatusCode::CREATED, response.status);
+        assert_eq!(
+            Some("https://updates.push.services.mozilla.com/m/gAAAAABfoo"),
+            response.location.as_deref()
+        );
+        assert_eq!(Some("gAAAAABfoo"), response.message_id());
+
+        let response = WebPushResponse::new(StatusCode::CREATED, &HeaderMap::new());
+
+        assert_eq!(None, response.location);
+        assert_eq!(None, response.message_id());
+    }
+
     #[test]
     fn parses_an_unauthorized_response_correctly() {
         assert_eq!(
-            Err(WebPushError::Unauthorized),
+            Err(WebPushError::Unauthorized(None)),
             parse_response(StatusCode::UNAUTHORIZED, vec![])
         )
     }
@@ -163,7 +399,7 @@ mod tests {
     #[test]
     fn parses_a_gone_response_correctly() {
         assert_eq!(
-            Err(WebPushError::EndpointNotValid),
+            Err(WebPushError::EndpointNotValid(None)),
             parse_response(StatusCode::GONE, vec![])
         )
     }
@@ -171,7 +407,7 @@ mod tests {
     #[test]
     fn parses_a_not_found_response_correctly() {
         assert_eq!(
-            Err(WebPushError::EndpointNotFound),
+            Err(WebPushError::EndpointNotFound(None)),
             parse_response(StatusCode::NOT_FOUND, vec![])
         )
     }
@@ -181,7 +417,14 @@ mod tests {
         assert_eq!(
             Err(WebPushError::PayloadTooLarge),
             parse_response(StatusCode::PAYLOAD_TOO_LARGE, vec![])
-        )
+        );
+        assert_eq!(
+            Err(WebPushError::PayloadTooLarge),
+            parse_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Payload exceeds the 2048 byte limit".as_bytes().to_vec()
+            )
+        );
     }
 
     #[test]
@@ -192,6 +435,14 @@ mod tests {
         )
     }
 
+    #[test]
+    fn parses_a_too_many_requests_response_correctly() {
+        assert_eq!(
+            Err(WebPushError::ServerError(None)),
+            parse_response(StatusCode::TOO_MANY_REQUESTS, vec![])
+        )
+    }
+
     #[test]
     fn parses_a_bad_request_response_with_no_body_correctly() {
         assert_eq!(
@@ -216,4 +467,53 @@ mod tests {
             parse_response(StatusCode::BAD_REQUEST, json.as_bytes().to_vec())
         )
     }
+
+    #[test]
+    fn parses_an_unauthorized_response_with_body_correctly() {
+        let body = r#"{"reason": "invalid JWT provided"}"#;
+
+        assert_eq!(
+            Err(WebPushError::Unauthorized(Some(body.to_string()))),
+            parse_response(StatusCode::UNAUTHORIZED, body.as_bytes().to_vec())
+        )
+    }
+
+    #[test]
+    fn parses_a_bad_request_response_with_text_body_correctly() {
+        assert_eq!(
+            Err(WebPushError::BadRequest(Some(String::from("Invalid registration")))),
+            parse_response(StatusCode::BAD_REQUEST, b"Invalid registration\n".to_vec())
+        )
+    }
+
+    #[test]
+    fn parses_a_forbidden_response_with_body_correctly() {
+        let body = "the key in the authorization header does not correspond to the sender ID used to subscribe";
+
+        assert_eq!(
+            Err(WebPushError::Forbidden(Some(body.to_string()))),
+            parse_response(StatusCode::FORBIDDEN, body.as_bytes().to_vec())
+        )
+    }
+
+    #[test]
+    fn parses_an_unknown_response_with_body_correctly() {
+        assert_eq!(
+            Err(WebPushError::Other(String::from("418: short and stout"))),
+            parse_response(StatusCode::IM_A_TEAPOT, b"short and stout".to_vec())
+        )
+    }
+
+    #[test]
+    fn parses_garbage_responses_without_panicking() {
+        let bodies: [&[u8]; 4] = [b"", b"\xff\xfe\x00", b"{\"error\": 5}", b"   \r\n"];
+
+        for code in 100..600 {
+            let status = StatusCode::from_u16(code).unwrap();
+
+            for body in bodies {
+                let _ = parse_response(status, body.to_vec());
+            }
+        }
+    }
 }
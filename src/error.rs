@@ -1,6 +1,8 @@
+use std::hash::{Hash, Hasher};
 use std::string::FromUtf8Error;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use std::{convert::From, error::Error, fmt, io::Error as IoError};
+use std::{cmp::Ordering, convert::From, error::Error, fmt, io::Error as IoError};
 
 use base64::DecodeError;
 use http::uri::InvalidUri;
@@ -10,28 +12,41 @@ use serde_json::error::Error as JsonError;
 pub enum WebPushError {
     /// An unknown error happened encrypting the message,
     Unspecified,
-    /// Please provide valid credentials to send the notification
-    Unauthorized,
-    /// Request was badly formed
+    /// Please provide valid credentials to send the notification. Returned for HTTP 401 Unauthorized, usually
+    /// because the VAPID signature is missing, malformed or expired. Contains the response body, if any.
+    Unauthorized(Option<String>),
+    /// The credentials were understood, but are not allowed to send to this subscription. Returned for HTTP 403
+    /// Forbidden, usually because the VAPID key is not the `applicationServerKey` the browser subscribed with, or
+    /// belongs to another project. Contains the response body, telling the reason, if any.
+    Forbidden(Option<String>),
+    /// Request was badly formed. Contains the error from the response body, if any.
     BadRequest(Option<String>),
-    /// Contains an optional `Duration`, until the user can retry the request
+    /// Contains an optional `Duration`, until the user can retry the request. Returned for 5xx and
+    /// 429 Too Many Requests responses, with the duration parsed from the `Retry-After` header.
     ServerError(Option<Duration>),
     /// The feature is not implemented yet
     NotImplemented,
-    /// The provided URI is invalid
-    InvalidUri,
-    /// The URL specified is no longer valid and should no longer be used
-    EndpointNotValid,
-    /// The URL specified is invalid and should not be used again
-    EndpointNotFound,
-    /// Maximum allowed payload size is 3800 characters
+    /// The endpoint is not an absolute `https` URL. Describes the problem with the endpoint.
+    InvalidUri(String),
+    /// The URL specified is no longer valid and should no longer be used. Returned for HTTP 410 Gone,
+    /// usually because the user unsubscribed. The subscription should be deleted. Contains the response
+    /// body, if any.
+    EndpointNotValid(Option<String>),
+    /// The URL specified is invalid and should not be used again. Returned for HTTP 404 Not Found,
+    /// usually because the subscription expired. The subscription should be deleted. Contains the response
+    /// body, if any.
+    EndpointNotFound(Option<String>),
+    /// Maximum allowed encrypted payload size is 4096 bytes. Returned both when the payload is too large to send,
+    /// and for HTTP 413 Payload Too Large from push services with a lower limit. The body of a 413 response, which
+    /// may tell the limit, is logged at debug level.
     PayloadTooLarge,
-    /// Could not initialize a TLS connection
-    TlsError,
+    /// Could not initialize a TLS connection, for example because of a bad server certificate. Contains the
+    /// underlying error. This is a transport error, see [`WebPushError::is_transport`].
+    TlsError(ErrorSource),
     /// Error in SSL signing
     SslError,
-    /// Error in reading a file
-    IoError,
+    /// Error in reading a file. Contains the underlying error.
+    IoError(ErrorSource),
     /// Make sure the message was addressed to a registration token whose
     /// package name matches the value passed in the request (Google).
     InvalidPackageName,
@@ -39,128 +54,423 @@ pub enum WebPushError {
     InvalidTtl,
     /// The request was missing required crypto keys
     MissingCryptoKeys,
-    /// One or more of the crypto key elements are invalid.
-    InvalidCryptoKeys,
+    /// One or more of the crypto key elements are invalid. Describes the problem with the key, if known.
+    InvalidCryptoKeys(Option<String>),
     /// Corrupted response data
     InvalidResponse,
     /// A claim had invalid data
     InvalidClaims,
+    /// The topic must be at most 32 characters from the URL-safe base64 alphabet
+    InvalidTopic,
+    /// The content encoding was not one of the supported ones
+    UnknownContentEncoding(String),
+    /// A custom header had an invalid name or value, or would replace a header of the protocol
+    InvalidHeader(String),
+    /// The request did not complete in time. This is a transport error, see [`WebPushError::is_transport`].
+    Timeout,
+    /// Could not reach the push service, because resolving its name, connecting or the connection itself failed.
+    /// Contains the underlying error. This is a transport error, see [`WebPushError::is_transport`].
+    ConnectionError(ErrorSource),
+    /// The payload could not be serialized to JSON. Contains the serialization error.
+    Json(ErrorSource),
+    /// The aes128gcm record size is too small to hold any content, it must be at least 18 bytes. Contains the
+    /// record size.
+    InvalidRecordSize(u32),
+    /// The push service redirected the message to another endpoint, which the client was not set to follow, see
+    /// [`RedirectPolicy`](crate::RedirectPolicy). Contains the absolute URL of the new endpoint, which the stored
+    /// subscription should be updated to. Like any endpoint it is a secret push token, so it is only given by
+    /// [`WebPushError::detail`], and left out when displaying the error.
+    Redirected(String),
     Other(String),
 }
 
-impl Error for WebPushError {}
+/// The underlying errors of [`WebPushError::IoError`], [`WebPushError::Json`], [`WebPushError::ConnectionError`]
+/// and [`WebPushError::TlsError`] are returned as the [`Error::source`]. Other underlying errors are logged at debug
+/// level when converted.
+impl Error for WebPushError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error_source()?.error().map(|err| err as &(dyn Error + 'static))
+    }
+}
+
+/// The underlying error of a [`WebPushError`], returned by [`Error::source`].
+///
+/// It is compared, ordered, hashed and serialized by the message of the error only, so that [`WebPushError`] stays
+/// plain data. An `ErrorSource` made from a message, or deserialized, has no underlying error.
+#[derive(Debug, Clone)]
+pub struct ErrorSource {
+    message: String,
+    error: Option<Arc<dyn Error + Send + Sync>>,
+}
+
+impl ErrorSource {
+    /// Keeps `error` as the source, with its display text as the message.
+    pub fn new<E: Error + Send + Sync + 'static>(error: E) -> ErrorSource {
+        ErrorSource {
+            message: error.to_string(),
+            error: Some(Arc::new(error)),
+        }
+    }
+
+    /// The message of the underlying error.
+    pub fn as_str(&self) -> &str {
+        &self.message
+    }
+
+    /// The underlying error, if it was kept.
+    pub fn error(&self) -> Option<&(dyn Error + Send + Sync + 'static)> {
+        self.error.as_deref()
+    }
+}
+
+impl From<String> for ErrorSource {
+    fn from(message: String) -> ErrorSource {
+        ErrorSource { message, error: None }
+    }
+}
+
+impl From<&str> for ErrorSource {
+    fn from(message: &str) -> ErrorSource {
+        ErrorSource::from(message.to_string())
+    }
+}
+
+impl fmt::Display for ErrorSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl PartialEq for ErrorSource {
+    fn eq(&self, other: &ErrorSource) -> bool {
+        self.message == other.message
+    }
+}
+
+impl Eq for ErrorSource {}
+
+impl PartialOrd for ErrorSource {
+    fn partial_cmp(&self, other: &ErrorSource) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ErrorSource {
+    fn cmp(&self, other: &ErrorSource) -> Ordering {
+        self.message.cmp(&other.message)
+    }
+}
+
+impl Hash for ErrorSource {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.message.hash(state);
+    }
+}
+
+impl serde::Serialize for ErrorSource {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.message)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ErrorSource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<ErrorSource, D::Error> {
+        String::deserialize(deserializer).map(ErrorSource::from)
+    }
+}
 
 impl From<JsonError> for WebPushError {
-    fn from(_: JsonError) -> WebPushError {
+    fn from(err: JsonError) -> WebPushError {
+        debug!("JSON error: {}", err);
         WebPushError::InvalidResponse
     }
 }
 
 impl From<FromUtf8Error> for WebPushError {
-    fn from(_: FromUtf8Error) -> WebPushError {
+    fn from(err: FromUtf8Error) -> WebPushError {
+        debug!("UTF-8 error: {}", err);
         WebPushError::InvalidResponse
     }
 }
 
 impl From<InvalidUri> for WebPushError {
-    fn from(_: InvalidUri) -> WebPushError {
-        WebPushError::InvalidUri
+    fn from(err: InvalidUri) -> WebPushError {
+        WebPushError::InvalidUri(format!("endpoint is not a valid URI: {}", err))
     }
 }
 
 #[cfg(feature = "hyper-client")]
 impl From<hyper::Error> for WebPushError {
-    fn from(_: hyper::Error) -> Self {
-        Self::Unspecified
+    fn from(err: hyper::Error) -> Self {
+        debug!("Hyper error: {}", err);
+
+        if err.is_connect() {
+            Self::ConnectionError(ErrorSource::new(err))
+        } else if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Unspecified
+        }
     }
 }
 
-#[cfg(not(feature = "hyper-client"))]
+#[cfg(all(feature = "isahc", not(feature = "hyper-client")))]
 impl From<isahc::Error> for WebPushError {
-    fn from(_: isahc::Error) -> Self {
-        Self::Unspecified
+    fn from(err: isahc::Error) -> Self {
+        debug!("Isahc error: {}", err);
+
+        if err.is_timeout() {
+            Self::Timeout
+        } else if err.is_tls() {
+            Self::TlsError(ErrorSource::new(err))
+        } else if err.is_network() {
+            Self::ConnectionError(ErrorSource::new(err))
+        } else {
+            Self::Unspecified
+        }
     }
 }
 
 impl From<IoError> for WebPushError {
-    fn from(_: IoError) -> WebPushError {
-        WebPushError::IoError
+    fn from(err: IoError) -> WebPushError {
+        debug!("IO error: {}", err);
+        WebPushError::IoError(ErrorSource::new(err))
     }
 }
 
 impl From<DecodeError> for WebPushError {
-    fn from(_: DecodeError) -> WebPushError {
-        WebPushError::InvalidCryptoKeys
+    fn from(err: DecodeError) -> WebPushError {
+        debug!("Base64 error: {}", err);
+        WebPushError::InvalidCryptoKeys(None)
     }
 }
 
 impl WebPushError {
+    /// Whether the message never got a response from the push service, because of a timeout, a connection failure
+    /// or a TLS failure. Errors for HTTP responses, like [`WebPushError::BadRequest`], are not transport errors.
+    ///
+    /// Timeouts and connection failures are usually worth retrying, while an error response will most likely be
+    /// the same on every attempt, unless it is a [`WebPushError::ServerError`].
+    pub fn is_transport(&self) -> bool {
+        matches!(
+            self,
+            WebPushError::Timeout | WebPushError::TlsError(_) | WebPushError::ConnectionError(_)
+        )
+    }
+
+    /// The kind of the error, without the details. Useful for counting errors by kind, as errors of the same kind
+    /// with different details don't compare equal.
+    pub fn kind(&self) -> WebPushErrorKind {
+        match *self {
+            WebPushError::Unspecified => WebPushErrorKind::Unspecified,
+            WebPushError::Unauthorized(_) => WebPushErrorKind::Unauthorized,
+            WebPushError::Forbidden(_) => WebPushErrorKind::Forbidden,
+            WebPushError::BadRequest(_) => WebPushErrorKind::BadRequest,
+            WebPushError::ServerError(_) => WebPushErrorKind::ServerError,
+            WebPushError::NotImplemented => WebPushErrorKind::NotImplemented,
+            WebPushError::InvalidUri(_) => WebPushErrorKind::InvalidUri,
+            WebPushError::EndpointNotValid(_) => WebPushErrorKind::EndpointNotValid,
+            WebPushError::EndpointNotFound(_) => WebPushErrorKind::EndpointNotFound,
+            WebPushError::PayloadTooLarge => WebPushErrorKind::PayloadTooLarge,
+            WebPushError::TlsError(_) => WebPushErrorKind::TlsError,
+            WebPushError::InvalidPackageName => WebPushErrorKind::InvalidPackageName,
+            WebPushError::InvalidTtl => WebPushErrorKind::InvalidTtl,
+            WebPushError::InvalidResponse => WebPushErrorKind::InvalidResponse,
+            WebPushError::MissingCryptoKeys => WebPushErrorKind::MissingCryptoKeys,
+            WebPushError::InvalidCryptoKeys(_) => WebPushErrorKind::InvalidCryptoKeys,
+            WebPushError::SslError => WebPushErrorKind::SslError,
+            WebPushError::IoError(_) => WebPushErrorKind::IoError,
+            WebPushError::Other(_) => WebPushErrorKind::Other,
+            WebPushError::InvalidClaims => WebPushErrorKind::InvalidClaims,
+            WebPushError::InvalidTopic => WebPushErrorKind::InvalidTopic,
+            WebPushError::InvalidHeader(_) => WebPushErrorKind::InvalidHeader,
+            WebPushError::UnknownContentEncoding(_) => WebPushErrorKind::UnknownContentEncoding,
+            WebPushError::Timeout => WebPushErrorKind::Timeout,
+            WebPushError::Json(_) => WebPushErrorKind::Json,
+            WebPushError::InvalidRecordSize(_) => WebPushErrorKind::InvalidRecordSize,
+            WebPushError::Redirected(_) => WebPushErrorKind::Redirected,
+            WebPushError::ConnectionError(_) => WebPushErrorKind::ConnectionError,
+        }
+    }
+
+    /// The text details of the error, like the response body of the push service, if any.
+    pub fn detail(&self) -> Option<&str> {
+        match self {
+            WebPushError::Unauthorized(Some(detail))
+            | WebPushError::Forbidden(Some(detail))
+            | WebPushError::InvalidCryptoKeys(Some(detail))
+            | WebPushError::BadRequest(Some(detail))
+            | WebPushError::EndpointNotValid(Some(detail))
+            | WebPushError::EndpointNotFound(Some(detail))
+            | WebPushError::InvalidHeader(detail)
+            | WebPushError::InvalidUri(detail)
+            | WebPushError::UnknownContentEncoding(detail)
+            | WebPushError::Redirected(detail)
+            | WebPushError::Other(detail) => Some(detail),
+            WebPushError::IoError(source)
+            | WebPushError::Json(source)
+            | WebPushError::ConnectionError(source)
+            | WebPushError::TlsError(source) => Some(source.as_str()),
+            _ => None,
+        }
+    }
+
+    fn error_source(&self) -> Option<&ErrorSource> {
+        match self {
+            WebPushError::IoError(source)
+            | WebPushError::Json(source)
+            | WebPushError::ConnectionError(source)
+            | WebPushError::TlsError(source) => Some(source),
+            _ => None,
+        }
+    }
+
     pub fn short_description(&self) -> &'static str {
+        self.kind().as_str()
+    }
+}
+
+/// The kinds of [`WebPushError`], see [`WebPushError::kind`].
+#[derive(PartialEq, Debug, Clone, Copy, Ord, PartialOrd, Eq, Hash)]
+pub enum WebPushErrorKind {
+    Unspecified,
+    Unauthorized,
+    Forbidden,
+    BadRequest,
+    ServerError,
+    NotImplemented,
+    InvalidUri,
+    EndpointNotValid,
+    EndpointNotFound,
+    PayloadTooLarge,
+    TlsError,
+    SslError,
+    IoError,
+    InvalidPackageName,
+    InvalidTtl,
+    MissingCryptoKeys,
+    InvalidCryptoKeys,
+    InvalidResponse,
+    InvalidClaims,
+    InvalidTopic,
+    UnknownContentEncoding,
+    InvalidHeader,
+    Timeout,
+    ConnectionError,
+    Json,
+    InvalidRecordSize,
+    Redirected,
+    Other,
+}
+
+impl WebPushErrorKind {
+    /// A short name of the kind, as returned by [`WebPushError::short_description`].
+    pub fn as_str(&self) -> &'static str {
         match *self {
-            WebPushError::Unspecified => "unspecified",
-            WebPushError::Unauthorized => "unauthorized",
-            WebPushError::BadRequest(_) => "bad_request",
-            WebPushError::ServerError(_) => "server_error",
-            WebPushError::NotImplemented => "not_implemented",
-            WebPushError::InvalidUri => "invalid_uri",
-            WebPushError::EndpointNotValid => "endpoint_not_valid",
-            WebPushError::EndpointNotFound => "endpoint_not_found",
-            WebPushError::PayloadTooLarge => "payload_too_large",
-            WebPushError::TlsError => "tls_error",
-            WebPushError::InvalidPackageName => "invalid_package_name",
-            WebPushError::InvalidTtl => "invalid_ttl",
-            WebPushError::InvalidResponse => "invalid_response",
-            WebPushError::MissingCryptoKeys => "missing_crypto_keys",
-            WebPushError::InvalidCryptoKeys => "invalid_crypto_keys",
-            WebPushError::SslError => "ssl_error",
-            WebPushError::IoError => "io_error",
-            WebPushError::Other(_) => "other",
-            WebPushError::InvalidClaims => "invalidClaims",
+            WebPushErrorKind::Unspecified => "unspecified",
+            WebPushErrorKind::Unauthorized => "unauthorized",
+            WebPushErrorKind::Forbidden => "forbidden",
+            WebPushErrorKind::BadRequest => "bad_request",
+            WebPushErrorKind::ServerError => "server_error",
+            WebPushErrorKind::NotImplemented => "not_implemented",
+            WebPushErrorKind::InvalidUri => "invalid_uri",
+            WebPushErrorKind::EndpointNotValid => "endpoint_not_valid",
+            WebPushErrorKind::EndpointNotFound => "endpoint_not_found",
+            WebPushErrorKind::PayloadTooLarge => "payload_too_large",
+            WebPushErrorKind::TlsError => "tls_error",
+            WebPushErrorKind::InvalidPackageName => "invalid_package_name",
+            WebPushErrorKind::InvalidTtl => "invalid_ttl",
+            WebPushErrorKind::InvalidResponse => "invalid_response",
+            WebPushErrorKind::MissingCryptoKeys => "missing_crypto_keys",
+            WebPushErrorKind::InvalidCryptoKeys => "invalid_crypto_keys",
+            WebPushErrorKind::SslError => "ssl_error",
+            WebPushErrorKind::IoError => "io_error",
+            WebPushErrorKind::Other => "other",
+            WebPushErrorKind::InvalidClaims => "invalidClaims",
+            WebPushErrorKind::InvalidTopic => "invalid_topic",
+            WebPushErrorKind::InvalidHeader => "invalid_header",
+            WebPushErrorKind::UnknownContentEncoding => "unknown_content_encoding",
+            WebPushErrorKind::Timeout => "timeout",
+            WebPushErrorKind::Json => "json",
+            WebPushErrorKind::InvalidRecordSize => "invalid_record_size",
+            WebPushErrorKind::Redirected => "redirected",
+            WebPushErrorKind::ConnectionError => "connection_error",
         }
     }
 }
 
+impl fmt::Display for WebPushErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
 impl fmt::Display for WebPushError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             WebPushError::Unspecified =>
                 write!(f, "An unknown error happened encrypting the message"),
-            WebPushError::Unauthorized =>
+            WebPushError::Unauthorized(_) =>
                 write!(f, "Please provide valid credentials to send the notification"),
+            WebPushError::Forbidden(_) =>
+                write!(f, "The credentials are not allowed to send to this subscription"),
             WebPushError::BadRequest(_) =>
                 write!(f, "Request was badly formed"),
             WebPushError::ServerError(_) =>
                 write!(f, "Server was unable to process the request, please try again later"),
             WebPushError::PayloadTooLarge =>
-                write!(f, "Maximum allowed payload size is 3070 characters"),
-            WebPushError::InvalidUri =>
+                write!(f, "Maximum allowed encrypted payload size is 4096 bytes"),
+            WebPushError::InvalidUri(_) =>
                 write!(f, "The provided URI is invalid"),
             WebPushError::NotImplemented =>
                 write!(f, "The feature is not implemented yet"),
-            WebPushError::EndpointNotValid =>
+            WebPushError::EndpointNotValid(_) =>
                 write!(f, "The URL specified is no longer valid and should no longer be used"),
-            WebPushError::EndpointNotFound =>
+            WebPushError::EndpointNotFound(_) =>
                 write!(f, "The URL specified is invalid and should not be used again"),
-            WebPushError::TlsError =>
+            WebPushError::TlsError(_) =>
                 write!(f, "Could not initialize a TLS connection"),
             WebPushError::SslError =>
                 write!(f, "Error signing with SSL"),
-            WebPushError::IoError =>
-                write!(f, "Error opening a file"),
+            WebPushError::IoError(_) =>
+                write!(f, "Error reading a file or stream"),
             WebPushError::InvalidPackageName =>
                 write!(f, "Make sure the message was addressed to a registration token whose package name matches the value passed in the request."),
             WebPushError::InvalidTtl => write!(f, "The TTL value provided was not valid or was not provided"),
-            WebPushError::InvalidResponse => write!(f, "The response data couldn't be parses"),
+            WebPushError::InvalidResponse => write!(f, "The response data couldn't be parsed"),
             WebPushError::MissingCryptoKeys  => write!(f, "The request is missing cryptographic keys"),
-            WebPushError::InvalidCryptoKeys  => write!(f, "The request is having invalid cryptographic keys"),
+            WebPushError::InvalidCryptoKeys(_)  => write!(f, "The request is having invalid cryptographic keys"),
             WebPushError::Other(_) => write!(f, "An unknown error when connecting the notification service"),
-            WebPushError::InvalidClaims => write!(f, "At least one JWT claim was invalid.")
+            WebPushError::InvalidClaims => write!(f, "At least one JWT claim was invalid."),
+            WebPushError::InvalidTopic => write!(f, "The topic must be at most 32 characters from the URL-safe base64 alphabet"),
+            WebPushError::UnknownContentEncoding(_) => write!(f, "The content encoding must be 'aes128gcm' or 'aesgcm'"),
+            WebPushError::InvalidHeader(_) => write!(f, "A custom header was invalid or would replace a protocol header"),
+            WebPushError::Timeout => write!(f, "The request did not complete in time"),
+            WebPushError::Json(_) => write!(f, "The payload could not be serialized to JSON"),
+            WebPushError::InvalidRecordSize(_) => write!(f, "The record size must be at least 18 bytes"),
+            WebPushError::Redirected(_) => write!(f, "The push service moved the subscription to another endpoint"),
+            WebPushError::ConnectionError(_) => write!(f, "Could not connect to the push service"),
+        }?;
+
+        //Add the details given by the push service, if any. An underlying error is left to the source, so that
+        //printing the error chain doesn't show it twice.
+        match (self, self.detail()) {
+            (WebPushError::Redirected(_), _) => Ok(()),
+            _ if self.error_source().and_then(ErrorSource::error).is_some() => Ok(()),
+            (_, Some(detail)) => write!(f, ": {}", detail),
+            (WebPushError::ServerError(Some(retry_after)), _) => {
+                write!(f, ", retry after {} seconds", retry_after.as_secs())
+            }
+            (WebPushError::InvalidRecordSize(record_size), _) => write!(f, ", not {}", record_size),
+            _ => Ok(()),
         }
     }
 }
 
 pub struct RetryAfter;
 impl RetryAfter {
+    /// Parses a `Retry-After` header value, either in seconds or as an HTTP date. Dates in the past
+    /// give a zero duration.
     pub fn from_str(header_value: &str) -> Option<Duration> {
         if let Ok(seconds) = header_value.parse::<u64>() {
             Some(Duration::from_secs(seconds))
@@ -177,3 +487,119 @@ impl RetryAfter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use std::error::Error;
+    use std::io;
+
+    use crate::error::{ErrorSource, RetryAfter, WebPushError, WebPushErrorKind};
+
+    #[test]
+    fn parses_retry_after_seconds() {
+        assert_eq!(Some(Duration::from_secs(120)), RetryAfter::from_str("120"));
+    }
+
+    #[test]
+    fn parses_retry_after_http_date() {
+        let in_an_hour: chrono::DateTime<chrono::Utc> = (SystemTime::now() + Duration::from_secs(3600)).into();
+        let header = in_an_hour.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+        let retry_after = RetryAfter::from_str(&header).unwrap();
+
+        assert!(retry_after > Duration::from_secs(3590) && retry_after <= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn parses_retry_after_past_http_date() {
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            RetryAfter::from_str("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+    }
+
+    #[test]
+    fn parses_invalid_retry_after() {
+        assert_eq!(None, RetryAfter::from_str("soon"));
+    }
+
+    #[test]
+    fn tells_transport_errors_from_responses() {
+        assert!(WebPushError::Timeout.is_transport());
+        assert!(WebPushError::TlsError(ErrorSource::from("bad certificate")).is_transport());
+        assert!(WebPushError::ConnectionError(ErrorSource::from("dns error")).is_transport());
+        assert!(!WebPushError::BadRequest(None).is_transport());
+        assert!(!WebPushError::ServerError(None).is_transport());
+        assert!(!WebPushError::PayloadTooLarge.is_transport());
+    }
+
+    #[test]
+    fn displays_error_details() {
+        assert_eq!(
+            "Request was badly formed: UnauthorizedRegistration",
+            WebPushError::BadRequest(Some(String::from("UnauthorizedRegistration"))).to_string()
+        );
+        assert_eq!(
+            "Server was unable to process the request, please try again later, retry after 30 seconds",
+            WebPushError::ServerError(Some(Duration::from_secs(30))).to_string()
+        );
+        assert_eq!(
+            "The request did not complete in time",
+            WebPushError::Timeout.to_string()
+        );
+        //The new endpoint is a secret, so it's only given by the detail.
+        assert_eq!(
+            "The push service moved the subscription to another endpoint",
+            WebPushError::Redirected(String::from("https://fcm.googleapis.com/fcm/send/bar")).to_string()
+        );
+    }
+
+    #[test]
+    fn splits_errors_into_kind_and_detail() {
+        let errors = [
+            WebPushError::Unauthorized(None),
+            WebPushError::Unauthorized(Some(String::from("expired token"))),
+            WebPushError::Timeout,
+        ];
+
+        let unauthorized = errors
+            .iter()
+            .filter(|e| e.kind() == WebPushErrorKind::Unauthorized)
+            .count();
+
+        assert_eq!(2, unauthorized);
+        assert_eq!(Some("expired token"), errors[1].detail());
+        assert_eq!(None, errors[0].detail());
+        assert_eq!("unauthorized", errors[1].kind().to_string());
+        assert_eq!(errors[1].short_description(), errors[1].kind().as_str());
+    }
+
+    #[test]
+    fn keeps_the_underlying_error_as_source() {
+        let error = WebPushError::from(io::Error::new(io::ErrorKind::UnexpectedEof, "stream ended"));
+
+        let source = error.source().unwrap();
+        assert_eq!("stream ended", source.to_string());
+        assert_eq!(
+            Some(io::ErrorKind::UnexpectedEof),
+            source.downcast_ref::<io::Error>().map(io::Error::kind)
+        );
+        assert_eq!(Some("stream ended"), error.detail());
+        assert_eq!("Error reading a file or stream", error.to_string());
+        assert_eq!(
+            "Error reading a file or stream: stream ended",
+            WebPushError::IoError(ErrorSource::from("stream ended")).to_string()
+        );
+
+        //The source is left out of comparing and serializing.
+        assert_eq!(WebPushError::IoError(ErrorSource::from("stream ended")), error.clone());
+        let json = serde_json::to_string(&error).unwrap();
+        assert_eq!(r#"{"IoError":"stream ended"}"#, json);
+
+        let deserialized: WebPushError = serde_json::from_str(&json).unwrap();
+        assert_eq!(error, deserialized);
+        assert!(deserialized.source().is_none());
+    }
+}
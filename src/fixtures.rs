@@ -0,0 +1,16 @@
+//! Fixtures shared by the tests.
+
+use crate::message::SubscriptionInfo;
+
+/// The endpoint of [`subscription_info`].
+pub(crate) const ENDPOINT: &str = "https://updates.push.services.mozilla.com/wpush/v2/foo";
+/// The `p256dh` of [`subscription_info`], a valid uncompressed P-256 public key.
+pub(crate) const P256DH: &str =
+    "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8";
+/// The `auth` of [`subscription_info`].
+pub(crate) const AUTH: &str = "xS03Fi5ErfTNH_l9WHE9Ig";
+
+/// A subscription to Mozilla's push service, with valid keys.
+pub(crate) fn subscription_info() -> SubscriptionInfo {
+    SubscriptionInfo::new(ENDPOINT, P256DH, AUTH)
+}
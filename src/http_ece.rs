@@ -1,21 +1,262 @@
-use ece::encrypt;
+//! Payload encryption, with the aes128gcm content encoding of [RFC8188](https://datatracker.ietf.org/doc/html/rfc8188)
+//! and [RFC8291](https://datatracker.ietf.org/doc/html/rfc8291), or the legacy aesgcm scheme.
+
+use std::fmt;
+use std::str::FromStr;
+
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::Aes128Gcm as Aes128GcmCipher;
+use base64::URL_SAFE_NO_PAD;
+use hkdf::Hkdf;
+use p256::elliptic_curve::ecdh::diffie_hellman;
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use p256::{PublicKey, SecretKey};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
 
 use crate::error::WebPushError;
 use crate::message::WebPushPayload;
+use crate::secret::Secret;
 use crate::vapid::VapidSignature;
 
+/// The largest encrypted payload push services are required to accept, in bytes.
+pub const MAX_PAYLOAD_SIZE: usize = 4096;
+
 /// Content encoding profiles.
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum ContentEncoding {
     //Make sure this enum remains exhaustive as that allows for easier migrations to new versions.
+    /// The current standard, [RFC8188](https://datatracker.ietf.org/doc/html/rfc8188). The salt, record size
+    /// and public key are all embedded in the body header.
     Aes128Gcm,
+    /// The legacy draft-03 scheme. The salt and public key are sent in the `Encryption` and `Crypto-Key` headers.
+    ///
+    /// Note: this should not be used unless you know for sure it is required. In all other cases, use
+    /// [`ContentEncoding::Aes128Gcm`].
+    AesGcm,
+    /// The recommended encoding. This is [`ContentEncoding::Aes128Gcm`], as every browser that still receives pushes
+    /// supports RFC8291, and the legacy GCM endpoints that only accepted [`ContentEncoding::AesGcm`] have been shut
+    /// down. Messages are built with aes128gcm, so set the encoding explicitly if a push service needs something
+    /// else.
+    ///
+    /// It parses from `auto`, but displays as `aes128gcm`, the header value it is sent with.
+    Auto,
+}
+
+impl ContentEncoding {
+    /// Gets the associated string for this content encoding, as would be used in the content-encoding header.
+    /// [`ContentEncoding::Auto`] is `aes128gcm`.
+    pub fn to_str(&self) -> &'static str {
+        match &self {
+            ContentEncoding::Aes128Gcm | ContentEncoding::Auto => "aes128gcm",
+            ContentEncoding::AesGcm => "aesgcm",
+        }
+    }
+
+    /// Resolves [`ContentEncoding::Auto`] to the encoding messages are built with, other encodings are returned as
+    /// they are.
+    pub(crate) fn resolve(self) -> ContentEncoding {
+        match self {
+            ContentEncoding::Auto => ContentEncoding::Aes128Gcm,
+            encoding => encoding,
+        }
+    }
+
+    /// Whether this encoding is deprecated. Only [`ContentEncoding::AesGcm`] is, as browsers now support the
+    /// standard [`ContentEncoding::Aes128Gcm`]. With the `tracing` feature, building a message with a deprecated
+    /// encoding also logs a warning.
+    pub fn is_deprecated(&self) -> bool {
+        matches!(self, ContentEncoding::AesGcm)
+    }
+
+    /// The longest content that fits in [`MAX_PAYLOAD_SIZE`] with this encoding, with [`Padding::Minimal`]. Longer
+    /// content can't be sent with any padding, so it is rejected without encrypting.
+    pub(crate) fn max_content_len(&self) -> usize {
+        match self {
+            //The header, the delimiter and the tag.
+            ContentEncoding::Aes128Gcm | ContentEncoding::Auto => {
+                MAX_PAYLOAD_SIZE - (SALT_LENGTH + 5 + 65) - 1 - TAG_LENGTH
+            }
+            //The padding length and the tag.
+            ContentEncoding::AesGcm => MAX_PAYLOAD_SIZE - 2 - TAG_LENGTH,
+        }
+    }
+}
+
+impl fmt::Display for ContentEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_str())
+    }
+}
+
+impl FromStr for ContentEncoding {
+    type Err = WebPushError;
+
+    /// Parses the encoding from its `Content-Encoding` header value, `aes128gcm` or `aesgcm`, or from `auto` for
+    /// [`ContentEncoding::Auto`], ignoring case. Anything else fails with [`WebPushError::UnknownContentEncoding`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "aes128gcm" => Ok(ContentEncoding::Aes128Gcm),
+            "aesgcm" => Ok(ContentEncoding::AesGcm),
+            "auto" => Ok(ContentEncoding::Auto),
+            _ => Err(WebPushError::UnknownContentEncoding(s.to_string())),
+        }
+    }
+}
+
+/// How much padding is added to the content before encrypting. Padding hides the exact length of the content
+/// from anyone seeing the encrypted message, at the cost of a larger message.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum Padding {
+    /// Pads the content to a multiple of 128 bytes. This is the default.
+    #[default]
+    Block,
+    /// Adds no more padding than the scheme requires, for the smallest messages.
+    Minimal,
+    /// Pads the content to exactly this many bytes, so all contents up to this length give messages of the same
+    /// size. Encrypting fails with [`WebPushError::PayloadTooLarge`] if the content is longer than this, or the
+    /// padded message would be too large to send.
+    PadTo(usize),
+}
+
+/// The length of the salt, in bytes.
+pub const SALT_LENGTH: usize = 16;
+/// The default record size written in the headers. A single record is enough for the largest allowed payload.
+pub const RECORD_SIZE: u32 = 4096;
+/// The smallest aes128gcm record size, fitting the tag and the delimiter with one byte of content.
+const MIN_RECORD_SIZE: u32 = 18;
+/// The length of the AES-GCM authentication tag added to each record.
+const TAG_LENGTH: usize = 16;
+/// Plaintext is padded to a multiple of this, to hide the exact length of the content.
+const PADDING_BLOCK_SIZE: usize = 128;
+
+/// The HKDF info for deriving the input keying material from the shared secret with aes128gcm, followed by the
+/// public keys of the receiver and the sender. [RFC8291, section 3.4](https://datatracker.ietf.org/doc/html/rfc8291#section-3.4).
+pub const WEBPUSH_INFO: &[u8] = b"WebPush: info\0";
+/// The HKDF info for deriving the content encryption key with aes128gcm.
+/// [RFC8188, section 2.2](https://datatracker.ietf.org/doc/html/rfc8188#section-2.2).
+pub const AES128GCM_KEY_INFO: &[u8] = b"Content-Encoding: aes128gcm\0";
+/// The HKDF info for deriving the nonce. Used as is with aes128gcm, and followed by the key context with aesgcm.
+/// [RFC8188, section 2.3](https://datatracker.ietf.org/doc/html/rfc8188#section-2.3).
+pub const NONCE_INFO: &[u8] = b"Content-Encoding: nonce\0";
+/// The HKDF info for deriving the input keying material from the shared secret with aesgcm.
+pub const AESGCM_AUTH_INFO: &[u8] = b"Content-Encoding: auth\0";
+/// The HKDF info for deriving the content encryption key with aesgcm, followed by the key context.
+pub const AESGCM_KEY_INFO: &[u8] = b"Content-Encoding: aesgcm\0";
+
+/// Everything that goes into encrypting a payload, for [`encrypt`].
+#[derive(Clone)]
+pub struct EncryptionParams<'a> {
+    /// The content encoding to encrypt with.
+    pub encoding: ContentEncoding,
+    /// The `p256dh` of the subscription, the uncompressed public key of the receiver.
+    pub peer_public_key: &'a [u8],
+    /// The `auth` of the subscription.
+    pub peer_secret: &'a [u8],
+    /// The salt. Must be random for each message.
+    pub salt: [u8; SALT_LENGTH],
+    /// The private key of the sender, the raw P-256 scalar. Must be random for each message.
+    pub local_private_key: [u8; 32],
+    /// The plaintext.
+    pub content: &'a [u8],
+    /// How much to pad the plaintext.
+    pub padding: Padding,
+    /// The record size of aes128gcm, usually [`RECORD_SIZE`]. Ignored with aesgcm, which has a single record.
+    pub record_size: u32,
+}
+
+impl fmt::Debug for EncryptionParams<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        //The private key and the auth secret are left out.
+        f.debug_struct("EncryptionParams")
+            .field("encoding", &self.encoding)
+            .field("peer_public_key", &self.peer_public_key)
+            .field("salt", &self.salt)
+            .field("content_len", &self.content.len())
+            .field("padding", &self.padding)
+            .field("record_size", &self.record_size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The result of [`encrypt`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedPayload {
+    /// The body of the request. With aes128gcm this starts with the header holding the salt, record size and
+    /// `local_public_key`. With aesgcm it is only the ciphertext, and those go in headers instead.
+    pub body: Vec<u8>,
+    /// The uncompressed public key of the sender, derived from the local private key.
+    pub local_public_key: Vec<u8>,
+}
+
+/// Encrypts a payload with a given salt and local key, for comparing the output byte for byte with other
+/// implementations, or the examples of the RFCs.
+///
+/// Only available with the `test-util` feature. The crate itself encrypts with a random salt and key for every message,
+/// and so must anything else using this: reusing them breaks the security of the encryption.
+#[cfg(any(test, feature = "test-util"))]
+pub fn encrypt(params: &EncryptionParams) -> Result<EncryptedPayload, WebPushError> {
+    let local_private_key = parse_local_private_key(&params.local_private_key)?;
+
+    let mut http_ece = HttpEce::new(params.encoding, params.peer_public_key, params.peer_secret, None);
+    http_ece.set_padding(params.padding);
+    http_ece.set_record_size(params.record_size);
+
+    http_ece.encrypt_with(&params.salt, &local_private_key, params.content)
+}
+
+/// The header at the start of an aes128gcm body, see [`parse_header`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Aes128GcmHeader {
+    /// The salt the content encryption key and nonce are derived with.
+    pub salt: [u8; SALT_LENGTH],
+    /// The size of each encrypted record, tag included.
+    pub record_size: u32,
+    /// The key id. For web push, this is the uncompressed public key of the sender.
+    pub key_id: Vec<u8>,
+    /// Where the encrypted records start in the body.
+    pub ciphertext_offset: usize,
+}
+
+/// Parses the header of an aes128gcm body: the 16 byte salt, the 4 byte record size, the 1 byte length of the key
+/// id and the key id, as laid out in [RFC8188, section 2.1](https://datatracker.ietf.org/doc/html/rfc8188#section-2.1).
+/// Returns `None` if the body is too short to hold the header.
+///
+/// This is meant for checking encrypted bodies in tests and while debugging; the header is not validated further.
+pub fn parse_header(body: &[u8]) -> Option<Aes128GcmHeader> {
+    if body.len() < SALT_LENGTH + 5 {
+        return None;
+    }
+
+    let (salt_bytes, rest) = body.split_at(SALT_LENGTH);
+    let (record_size_bytes, rest) = rest.split_at(4);
+    let key_id_length = rest[0] as usize;
+    let key_id = rest.get(1..1 + key_id_length)?;
+
+    let mut salt = [0u8; SALT_LENGTH];
+    salt.copy_from_slice(salt_bytes);
+    let mut record_size = [0u8; 4];
+    record_size.copy_from_slice(record_size_bytes);
+
+    Some(Aes128GcmHeader {
+        salt,
+        record_size: u32::from_be_bytes(record_size),
+        key_id: key_id.to_vec(),
+        ciphertext_offset: SALT_LENGTH + 5 + key_id_length,
+    })
 }
 
 /// Struct for handling payload encryption.
-pub struct HttpEce<'a> {
+pub(crate) struct HttpEce<'a> {
     peer_public_key: &'a [u8],
     peer_secret: &'a [u8],
     encoding: ContentEncoding,
     vapid_signature: Option<VapidSignature>,
+    salt: Option<[u8; SALT_LENGTH]>,
+    local_private_key: Option<Secret<[u8; 32]>>,
+    padding: Padding,
+    record_size: u32,
 }
 
 impl<'a> HttpEce<'a> {
@@ -34,74 +275,348 @@ impl<'a> HttpEce<'a> {
             peer_secret,
             encoding,
             vapid_signature,
+            salt: None,
+            local_private_key: None,
+            padding: Padding::default(),
+            record_size: RECORD_SIZE,
         }
     }
 
-    /// Encrypts a payload. The maximum length for the payload is 3800
-    /// characters, which is the largest that works with Google's and Mozilla's
-    /// push servers.
+    /// Sets how much padding is added to the content.
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    /// Sets the aes128gcm record size, splitting the content into records of this many bytes. Push messages should
+    /// be a single record, see
+    /// [`WebPushMessageBuilder::set_record_size`](crate::WebPushMessageBuilder::set_record_size).
+    pub fn set_record_size(&mut self, record_size: u32) {
+        self.record_size = record_size;
+    }
+
+    /// Uses a fixed salt instead of a random one. Only for reproducible tests.
+    pub fn set_salt(&mut self, salt: [u8; SALT_LENGTH]) {
+        self.salt = Some(salt);
+    }
+
+    /// Uses a fixed local private key, the raw P-256 scalar, instead of a random one. Only for reproducible tests.
+    pub fn set_local_private_key(&mut self, private_key: Secret<[u8; 32]>) {
+        self.local_private_key = Some(private_key);
+    }
+
+    /// Encrypts a payload. The encrypted payload, including padding, the encryption header
+    /// and the authentication tag, can be at most [`MAX_PAYLOAD_SIZE`] bytes, which is the largest
+    /// that works with Google's and Mozilla's push servers. This allows for plaintexts of up to
+    /// 3967 bytes with aes128gcm, and 3966 bytes with aesgcm.
     pub fn encrypt(&self, content: &'a [u8]) -> Result<WebPushPayload, WebPushError> {
-        if content.len() > 3052 {
+        let salt = self.salt.unwrap_or_else(|| {
+            let mut salt = [0u8; SALT_LENGTH];
+            OsRng.fill_bytes(&mut salt);
+            salt
+        });
+
+        //The key stays a SecretKey, which is wiped when dropped, and is never copied out as bytes.
+        let local_private_key = match &self.local_private_key {
+            Some(bytes) => parse_local_private_key(bytes)?,
+            None => SecretKey::random(&mut OsRng),
+        };
+
+        let encrypted = self.encrypt_with(&salt, &local_private_key, content)?;
+
+        //Check the size of what actually goes on the wire.
+        if encrypted.body.len() > MAX_PAYLOAD_SIZE {
             return Err(WebPushError::PayloadTooLarge);
         }
 
         //Add more encoding standards to this match as they are created.
-        match self.encoding {
-            ContentEncoding::Aes128Gcm => {
-                let result = encrypt(self.peer_public_key, self.peer_secret, content);
-
-                let mut headers = Vec::new();
-
-                //VAPID uses a special Authorisation header, which contains a ecdhsa key and a jwt.
-                if let Some(signature) = &self.vapid_signature {
-                    headers.push((
-                        "Authorization",
-                        format!(
-                            "vapid t={}, k={}",
-                            signature.auth_t,
-                            base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD)
-                        ),
-                    ));
+        let crypto_headers = match self.encoding {
+            //Everything needed for decryption is in the body, so no extra headers.
+            ContentEncoding::Aes128Gcm | ContentEncoding::Auto => Vec::new(),
+            //The salt and dh key live in the Encryption and Crypto-Key headers for this scheme, along with
+            //the VAPID public key.
+            ContentEncoding::AesGcm => {
+                let dh = base64::encode_config(&encrypted.local_public_key, URL_SAFE_NO_PAD);
+                let crypto_key = match &self.vapid_signature {
+                    Some(signature) => format!(
+                        "dh={}; p256ecdsa={}",
+                        dh,
+                        base64::encode_config(&signature.auth_k, URL_SAFE_NO_PAD)
+                    ),
+                    None => format!("dh={}", dh),
+                };
+                let encryption = format!(
+                    "salt={};rs={}",
+                    base64::encode_config(salt, URL_SAFE_NO_PAD),
+                    RECORD_SIZE
+                );
+
+                vec![("Crypto-Key", crypto_key), ("Encryption", encryption)]
+            }
+        };
+
+        Ok(WebPushPayload {
+            content: encrypted.body,
+            crypto_headers,
+            content_encoding: self.encoding.to_str(),
+        })
+    }
+
+    /// Encrypts `content` with the given salt and local key, without checking the size of the body.
+    fn encrypt_with(
+        &self,
+        salt: &[u8; SALT_LENGTH],
+        local_private_key: &SecretKey,
+        content: &[u8],
+    ) -> Result<EncryptedPayload, WebPushError> {
+        let peer_public_key = PublicKey::from_sec1_bytes(self.peer_public_key)
+            .map_err(|_| WebPushError::InvalidCryptoKeys(Some(String::from("p256dh is not a valid P-256 key"))))?;
+
+        let peer_public_key_bytes = peer_public_key.to_encoded_point(false);
+        let local_public_key_bytes = local_private_key.public_key().to_encoded_point(false);
+
+        let shared_secret = diffie_hellman(local_private_key.to_nonzero_scalar(), peer_public_key.as_affine());
+
+        //Add more encoding standards to this match as they are created.
+        let body = match self.encoding {
+            ContentEncoding::Aes128Gcm | ContentEncoding::Auto => {
+                //RFC8291, section 3.4. Both public keys go into the info of the input keying material.
+                let mut key_info = WEBPUSH_INFO.to_vec();
+                key_info.extend_from_slice(peer_public_key_bytes.as_bytes());
+                key_info.extend_from_slice(local_public_key_bytes.as_bytes());
+
+                if self.record_size < MIN_RECORD_SIZE {
+                    return Err(WebPushError::InvalidRecordSize(self.record_size));
                 }
 
-                match result {
-                    Ok(data) => Ok(WebPushPayload {
-                        content: data,
-                        crypto_headers: headers,
-                        content_encoding: "aes128gcm",
-                    }),
-                    _ => Err(WebPushError::InvalidCryptoKeys),
+                let ikm = hkdf_sha256(self.peer_secret, shared_secret.as_bytes(), &key_info, 32)?;
+                let key = hkdf_sha256(salt, &ikm, AES128GCM_KEY_INFO, 16)?;
+                let nonce = hkdf_sha256(salt, &ikm, NONCE_INFO, 12)?;
+
+                //The content followed by the padding, the delimiters are added per record.
+                let mut plaintext = Secret::new(content.to_vec());
+                plaintext.resize(content.len() + padding_length(self.padding, content.len(), 1)? - 1, 0);
+
+                //RFC8188, section 2. Every record but the last is full, and ends its content with the 0x01 delimiter.
+                //The last one uses 0x02. With the default record size, the largest payload fits in a single record.
+                let capacity = self.record_size as usize - TAG_LENGTH - 1;
+                let chunks: Vec<&[u8]> = if plaintext.is_empty() {
+                    vec![&[]]
+                } else {
+                    plaintext.chunks(capacity).collect()
+                };
+
+                let mut ciphertext = Vec::new();
+                for (seq, chunk) in chunks.iter().enumerate() {
+                    let content_len = content.len().saturating_sub(seq * capacity).min(chunk.len());
+                    let delimiter = if seq == chunks.len() - 1 { 2 } else { 1 };
+
+                    let mut record = Secret::new(chunk[..content_len].to_vec());
+                    record.push(delimiter);
+                    record.extend_from_slice(&chunk[content_len..]);
+
+                    ciphertext.extend_from_slice(&aes_128_gcm(&key, &record_nonce(&nonce, seq), &record)?);
                 }
+
+                //RFC8188, section 2.1. The header has the salt, record size and sender public key.
+                let mut body = Vec::with_capacity(SALT_LENGTH + 5 + local_public_key_bytes.len() + ciphertext.len());
+                body.extend_from_slice(salt);
+                body.extend_from_slice(&self.record_size.to_be_bytes());
+                body.push(local_public_key_bytes.len() as u8);
+                body.extend_from_slice(local_public_key_bytes.as_bytes());
+                body.extend_from_slice(&ciphertext);
+                body
+            }
+            ContentEncoding::AesGcm => {
+                //draft-ietf-webpush-encryption-04. Both public keys, length prefixed, are the context of the keys.
+                let mut context = b"P-256\0".to_vec();
+                for public_key in [peer_public_key_bytes.as_bytes(), local_public_key_bytes.as_bytes()].iter() {
+                    context.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+                    context.extend_from_slice(public_key);
+                }
+
+                let key_info = [AESGCM_KEY_INFO, &context].concat();
+                let nonce_info = [NONCE_INFO, &context].concat();
+
+                let ikm = hkdf_sha256(self.peer_secret, shared_secret.as_bytes(), AESGCM_AUTH_INFO, 32)?;
+                let key = hkdf_sha256(salt, &ikm, &key_info, 16)?;
+                let nonce = hkdf_sha256(salt, &ikm, &nonce_info, 12)?;
+
+                //The padding goes first, starting with its length as two bytes.
+                let padding = padding_length(self.padding, content.len(), 2)?;
+
+                //This scheme only supports a single record, which caps the plaintext size.
+                if content.len() + padding >= RECORD_SIZE as usize {
+                    return Err(WebPushError::PayloadTooLarge);
+                }
+
+                let mut record = Secret::new(((padding - 2) as u16).to_be_bytes().to_vec());
+                record.resize(padding, 0);
+                record.extend_from_slice(content);
+
+                aes_128_gcm(&key, &nonce, &record)?
+            }
+        };
+
+        Ok(EncryptedPayload {
+            body,
+            local_public_key: local_public_key_bytes.as_bytes().to_vec(),
+        })
+    }
+}
+
+/// Reads a raw P-256 scalar given as the local private key.
+fn parse_local_private_key(bytes: &[u8; 32]) -> Result<SecretKey, WebPushError> {
+    SecretKey::from_be_bytes(bytes)
+        .map_err(|_| WebPushError::InvalidCryptoKeys(Some(String::from("local key is out of range for P-256"))))
+}
+
+/// Gets how much padding to add to the content for the padding policy. The padding is at least `min_length` bytes,
+/// as the schemes store some data in it, and doesn't count towards the length of [`Padding::PadTo`].
+fn padding_length(padding: Padding, content_length: usize, min_length: usize) -> Result<usize, WebPushError> {
+    match padding {
+        Padding::Block => {
+            let padding = PADDING_BLOCK_SIZE - (content_length % PADDING_BLOCK_SIZE);
+
+            if padding < min_length {
+                Ok(padding + PADDING_BLOCK_SIZE)
+            } else {
+                Ok(padding)
             }
         }
+        Padding::Minimal => Ok(min_length),
+        //Lengths that fit in a message are caught by the size checks after padding.
+        Padding::PadTo(length) if length >= content_length && length <= MAX_PAYLOAD_SIZE => {
+            Ok(length - content_length + min_length)
+        }
+        Padding::PadTo(_) => Err(WebPushError::PayloadTooLarge),
     }
 }
 
+/// The nonce of the record with sequence number `seq`, which is XORed into the end of the nonce.
+/// [RFC8188, section 2.3](https://datatracker.ietf.org/doc/html/rfc8188#section-2.3).
+fn record_nonce(nonce: &[u8], seq: usize) -> Secret<Vec<u8>> {
+    let mut nonce = Secret::new(nonce.to_vec());
+
+    for (byte, seq_byte) in nonce.iter_mut().rev().zip((seq as u64).to_be_bytes().iter().rev()) {
+        *byte ^= seq_byte;
+    }
+
+    nonce
+}
+
+/// Derives `length` bytes from the input keying material with HKDF-SHA256.
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], length: usize) -> Result<Secret<Vec<u8>>, WebPushError> {
+    let mut okm = Secret::new(vec![0u8; length]);
+
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut okm)
+        .map_err(|_| WebPushError::InvalidCryptoKeys(None))?;
+
+    Ok(okm)
+}
+
+/// Encrypts a single record with AES-128-GCM. The ciphertext has the authentication tag appended.
+fn aes_128_gcm(key: &[u8], nonce: &[u8], record: &[u8]) -> Result<Vec<u8>, WebPushError> {
+    let ciphertext = Aes128GcmCipher::new(GenericArray::from_slice(key))
+        .encrypt(GenericArray::from_slice(nonce), record)
+        .map_err(|_| WebPushError::InvalidCryptoKeys(None))?;
+
+    debug_assert_eq!(record.len() + TAG_LENGTH, ciphertext.len());
+
+    Ok(ciphertext)
+}
+
 #[cfg(test)]
 mod tests {
     use base64::{self, URL_SAFE};
-    use regex::Regex;
 
     use crate::error::WebPushError;
-    use crate::http_ece::{ContentEncoding, HttpEce};
+    use crate::fixtures::{AUTH, P256DH};
+    use crate::http_ece::{
+        encrypt, parse_header, ContentEncoding, EncryptionParams, HttpEce, Padding, MAX_PAYLOAD_SIZE, RECORD_SIZE,
+        SALT_LENGTH, TAG_LENGTH,
+    };
+    use crate::secret::Secret;
     use crate::VapidSignature;
     use crate::WebPushPayload;
 
+    #[test]
+    fn test_content_encoding_from_str() {
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            assert_eq!(Ok(encoding), encoding.to_string().parse());
+        }
+
+        assert_eq!(Ok(ContentEncoding::Aes128Gcm), "AES128GCM".parse());
+        assert_eq!(Ok(ContentEncoding::Auto), "auto".parse());
+        assert_eq!(
+            Err(WebPushError::UnknownContentEncoding(String::from("gzip"))),
+            "gzip".parse::<ContentEncoding>()
+        );
+    }
+
+    #[test]
+    fn test_content_encoding_resolve() {
+        assert_eq!(ContentEncoding::Aes128Gcm, ContentEncoding::Auto.resolve());
+        assert_eq!(ContentEncoding::Aes128Gcm, ContentEncoding::Aes128Gcm.resolve());
+        assert_eq!(ContentEncoding::AesGcm, ContentEncoding::AesGcm.resolve());
+        assert_eq!("aes128gcm", ContentEncoding::Auto.to_str());
+    }
+
+    #[test]
+    fn test_content_encoding_is_deprecated() {
+        assert!(ContentEncoding::AesGcm.is_deprecated());
+        assert!(!ContentEncoding::Aes128Gcm.is_deprecated());
+    }
+
+    #[test]
+    fn test_max_content_len() {
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
+
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            let mut http_ece = HttpEce::new(encoding, &p256dh, &auth, None);
+            http_ece.set_padding(Padding::Minimal);
+
+            let content = vec![0; encoding.max_content_len()];
+
+            assert_eq!(MAX_PAYLOAD_SIZE, http_ece.encrypt(&content).unwrap().content.len());
+            assert_eq!(
+                Err(WebPushError::PayloadTooLarge),
+                http_ece.encrypt(&vec![0; content.len() + 1]).map(|_| ())
+            );
+        }
+    }
+
     #[test]
     fn test_payload_too_big() {
-        let p256dh = base64::decode_config(
-            "BLMaF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
-            URL_SAFE,
-        )
-        .unwrap();
-        let auth = base64::decode_config("xS03Fj5ErfTNH_l9WHE9Ig", URL_SAFE).unwrap();
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
         let http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, &p256dh, &auth, None);
         //This content is one above limit.
-        let content = [0u8; 3801];
+        let content = [0u8; 3968];
+
+        assert_eq!(Err(WebPushError::PayloadTooLarge), http_ece.encrypt(&content));
+
+        let http_ece = HttpEce::new(ContentEncoding::AesGcm, &p256dh, &auth, None);
+        let content = [0u8; 3967];
 
         assert_eq!(Err(WebPushError::PayloadTooLarge), http_ece.encrypt(&content));
     }
 
+    #[test]
+    fn test_payload_max_size() {
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
+
+        let http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, &p256dh, &auth, None);
+        let payload = http_ece.encrypt(&[0u8; 3967]).unwrap();
+        assert!(payload.content.len() <= MAX_PAYLOAD_SIZE);
+
+        let http_ece = HttpEce::new(ContentEncoding::AesGcm, &p256dh, &auth, None);
+        let payload = http_ece.encrypt(&[0u8; 3966]).unwrap();
+        assert!(payload.content.len() <= MAX_PAYLOAD_SIZE);
+    }
+
     /// Tests that the content encryption is properly reversible while using aes128gcm.
     #[test]
     fn test_payload_encrypts_128() {
@@ -122,13 +637,71 @@ mod tests {
         )
     }
 
+    /// Tests that content split into several records decrypts, with the record size in the header.
+    #[test]
+    fn test_payload_encrypts_records() {
+        let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let components = key.raw_components().unwrap();
+
+        for record_size in [18, 19, 100, 145, 4096] {
+            for padding in [Padding::Minimal, Padding::Block] {
+                for len in [0usize, 1, 127, 128, 300] {
+                    let content: Vec<u8> = (0..len).map(|i| i as u8 | 1).collect();
+
+                    let mut http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, components.public_key(), &auth, None);
+                    http_ece.set_padding(padding);
+                    http_ece.set_record_size(record_size);
+
+                    //The content and the padding, without the delimiters, which go into each record.
+                    let plaintext_len = match padding {
+                        Padding::Minimal => len,
+                        _ => (len / 128 + 1) * 128 - 1,
+                    };
+                    let capacity = record_size as usize - TAG_LENGTH - 1;
+                    let records = plaintext_len.div_ceil(capacity).max(1);
+
+                    //Each record adds a delimiter and a tag, so tiny records can make the message too large to send.
+                    let body_len = SALT_LENGTH + 5 + 65 + plaintext_len + records * (TAG_LENGTH + 1);
+                    let result = http_ece.encrypt(&content);
+
+                    if body_len > MAX_PAYLOAD_SIZE {
+                        assert_eq!(
+                            Err(WebPushError::PayloadTooLarge),
+                            result,
+                            "record size {}, {:?}, {} bytes",
+                            record_size,
+                            padding,
+                            len
+                        );
+                        continue;
+                    }
+
+                    let payload = result.unwrap();
+
+                    assert_eq!(body_len, payload.content.len());
+
+                    assert_eq!(record_size, parse_header(&payload.content).unwrap().record_size);
+                    assert_eq!(
+                        content,
+                        ece::decrypt(&components, &auth, &payload.content).unwrap(),
+                        "record size {}, {:?}, {} bytes",
+                        record_size,
+                        padding,
+                        len
+                    );
+                }
+            }
+        }
+
+        let mut http_ece = HttpEce::new(ContentEncoding::Aes128Gcm, components.public_key(), &auth, None);
+        http_ece.set_record_size(17);
+
+        assert_eq!(Err(WebPushError::InvalidRecordSize(17)), http_ece.encrypt(b"test"));
+    }
+
     fn setup_payload(vapid_signature: Option<VapidSignature>, encoding: ContentEncoding) -> WebPushPayload {
-        let p256dh = base64::decode_config(
-            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
-            URL_SAFE,
-        )
-        .unwrap();
-        let auth = base64::decode_config("xS03Fi5ErfTNH_l9WHE9Ig", URL_SAFE).unwrap();
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
 
         let http_ece = HttpEce::new(encoding, &p256dh, &auth, vapid_signature);
         let content = "Hello, world!".as_bytes();
@@ -144,15 +717,269 @@ mod tests {
 
     #[test]
     fn test_aes128gcm_headers_vapid() {
-        let auth_re = Regex::new(r"vapid t=(?P<sig_t>[^,]*), k=(?P<sig_k>[^,]*)").unwrap();
         let vapid_signature = VapidSignature {
             auth_t: String::from("foo"),
             auth_k: String::from("bar").into_bytes(),
         };
         let wp_payload = setup_payload(Some(vapid_signature), ContentEncoding::Aes128Gcm);
-        assert_eq!(wp_payload.crypto_headers.len(), 1);
-        let auth = wp_payload.crypto_headers[0].clone();
-        assert_eq!(auth.0, "Authorization");
-        assert!(auth_re.captures(&auth.1).is_some());
+        //The Authorization header comes from the message, not the payload.
+        assert_eq!(wp_payload.crypto_headers.len(), 0);
+    }
+
+    #[test]
+    fn test_aesgcm_headers_no_vapid() {
+        let wp_payload = setup_payload(None, ContentEncoding::AesGcm);
+        assert_eq!(wp_payload.content_encoding, "aesgcm");
+        assert_eq!(wp_payload.crypto_headers.len(), 2);
+
+        let names: Vec<&str> = wp_payload.crypto_headers.iter().map(|(k, _)| *k).collect();
+        assert!(names.contains(&"Crypto-Key"));
+        assert!(names.contains(&"Encryption"));
+    }
+
+    #[test]
+    fn test_aesgcm_headers_vapid() {
+        let vapid_signature = VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        };
+        let wp_payload = setup_payload(Some(vapid_signature), ContentEncoding::AesGcm);
+        assert_eq!(wp_payload.crypto_headers.len(), 2);
+
+        let crypto_key = wp_payload
+            .crypto_headers
+            .iter()
+            .find(|(k, _)| *k == "Crypto-Key")
+            .map(|(_, v)| v.clone())
+            .unwrap();
+        assert!(crypto_key.contains("p256ecdsa="));
+    }
+
+    /// Tests that the content encryption is properly reversible while using aesgcm.
+    #[test]
+    fn test_payload_encrypts_aesgcm() {
+        let (key, auth) = ece::generate_keypair_and_auth_secret().unwrap();
+        let p_key = key.raw_components().unwrap();
+        let p_key = p_key.public_key();
+
+        let http_ece = HttpEce::new(ContentEncoding::AesGcm, p_key, &auth, None);
+        let plaintext = "Hello world!";
+        let ciphertext = http_ece.encrypt(plaintext.as_bytes()).unwrap();
+
+        let header = |name: &str, param: &str| -> Vec<u8> {
+            let value = ciphertext
+                .crypto_headers
+                .iter()
+                .find(|(k, _)| *k == name)
+                .map(|(_, v)| v.clone())
+                .unwrap();
+            let param = value
+                .split(';')
+                .map(|p| p.trim())
+                .find_map(|p| p.strip_prefix(param))
+                .unwrap()
+                .to_string();
+            base64::decode_config(param, URL_SAFE).unwrap()
+        };
+
+        let block = ece::legacy::AesGcmEncryptedBlock::new(
+            &header("Crypto-Key", "dh="),
+            &header("Encryption", "salt="),
+            4096,
+            ciphertext.content.clone(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(ece::legacy::decrypt_aesgcm(&key.raw_components().unwrap(), &auth, &block).unwrap())
+                .unwrap(),
+            plaintext
+        )
+    }
+
+    #[test]
+    fn test_encrypt_matches_http_ece() {
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
+
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            let encrypted = encrypt(&EncryptionParams {
+                encoding,
+                peer_public_key: &p256dh,
+                peer_secret: &auth,
+                salt: [1; 16],
+                local_private_key: [2; 32],
+                content: b"test",
+                padding: Padding::default(),
+                record_size: RECORD_SIZE,
+            })
+            .unwrap();
+
+            let mut http_ece = HttpEce::new(encoding, &p256dh, &auth, None);
+            http_ece.set_salt([1; 16]);
+            http_ece.set_local_private_key(Secret::new([2; 32]));
+
+            assert_eq!(http_ece.encrypt(b"test").unwrap().content, encrypted.body);
+            assert_eq!(65, encrypted.local_public_key.len());
+
+            if encoding == ContentEncoding::Aes128Gcm {
+                assert_eq!(&encrypted.local_public_key[..], &encrypted.body[21..86]);
+            }
+        }
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn test_wipes_the_local_private_key_on_drop() {
+        use std::mem::{size_of, MaybeUninit};
+        use std::{ptr, slice};
+
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
+        let key = [171; 32];
+
+        //The encryptor lives in storage that outlasts it, so the memory that held the key can be read after the drop.
+        let mut storage = MaybeUninit::new(HttpEce::new(ContentEncoding::Aes128Gcm, &p256dh, &auth, None));
+        let http_ece = storage.as_mut_ptr();
+
+        //Safety: the storage was initialized just above, and the encryptor is only dropped once, below.
+        unsafe {
+            (*http_ece).set_local_private_key(Secret::new(key));
+            (*http_ece).encrypt(b"test").unwrap();
+        }
+
+        let field = unsafe { ptr::addr_of!((*http_ece).local_private_key) } as *const u8;
+        //Safety: the field is an array behind an option tag, so every byte is initialized, and the storage stays
+        //allocated after the encryptor is dropped.
+        let holds_key = || {
+            unsafe { slice::from_raw_parts(field, size_of::<Option<Secret<[u8; 32]>>>()) }
+                .windows(32)
+                .any(|window| window == key)
+        };
+
+        assert!(holds_key());
+        unsafe { ptr::drop_in_place(http_ece) };
+        assert!(!holds_key());
+    }
+
+    #[test]
+    fn test_encryption_params_debug_hides_secrets() {
+        let params = EncryptionParams {
+            encoding: ContentEncoding::Aes128Gcm,
+            peer_public_key: &[4; 65],
+            peer_secret: &[205; 16],
+            salt: [1; 16],
+            local_private_key: [171; 32],
+            content: b"test",
+            padding: Padding::default(),
+            record_size: RECORD_SIZE,
+        };
+
+        let debug = format!("{:?}", params);
+
+        assert!(!debug.contains("171"));
+        assert!(!debug.contains("205"));
+    }
+
+    /// The example of [RFC8291, section 5](https://datatracker.ietf.org/doc/html/rfc8291#section-5).
+    #[test]
+    fn test_rfc8291_example() {
+        let decode = |value: &str| base64::decode_config(value, URL_SAFE).unwrap();
+
+        let mut salt = [0u8; 16];
+        salt.copy_from_slice(&decode("DGv6ra1nlYgDCS1FRnbzlw"));
+        let mut local_private_key = [0u8; 32];
+        local_private_key.copy_from_slice(&decode("yfWPiYE-n46HLnH0KqZOF1fJJU3MYrct3AELtAQ-oRw"));
+
+        let peer_public_key =
+            decode("BCVxsr7N_eNgVRqvHtD0zTZsEc6-VV-JvLexhqUzORcxaOzi6-AYWXvTBHm4bjyPjs7Vd8pZGH6SRpkNtoIAiw4");
+        let peer_secret = decode("BTBZMqHH6r4Tts7J_aSIgg");
+
+        let encrypted = encrypt(&EncryptionParams {
+            encoding: ContentEncoding::Aes128Gcm,
+            peer_public_key: &peer_public_key,
+            peer_secret: &peer_secret,
+            salt,
+            local_private_key,
+            content: b"When I grow up, I want to be a watermelon",
+            padding: Padding::Minimal,
+            record_size: RECORD_SIZE,
+        })
+        .unwrap();
+
+        let expected = decode(concat!(
+            "DGv6ra1nlYgDCS1FRnbzlwAAEABBBP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK",
+            "6PBru3jl7A_yl95bQpu6cVPTpK4Mqgkf1CXztLVBSt2Ks3oZwbuwXPXLWyouBWLVWGNWQexSgSxsj_Qulcy4a-fN",
+        ));
+
+        assert_eq!(
+            decode("BP4z9KsN6nGRTbVYI_c7VJSPQTBtkgcy27mlmlMoZIIgDll6e3vCYLocInmYWAmS6TlzAC8wEqKK6PBru3jl7A8"),
+            encrypted.local_public_key
+        );
+
+        assert_eq!(expected, encrypted.body);
+
+        let header = parse_header(&encrypted.body).unwrap();
+
+        assert_eq!(salt, header.salt);
+        assert_eq!(4096, header.record_size);
+        assert_eq!(encrypted.local_public_key, header.key_id);
+        assert_eq!(86, header.ciphertext_offset);
+    }
+
+    #[test]
+    fn test_parse_truncated_header() {
+        //Too short for the salt and record size, and a key id length pointing past the end.
+        assert_eq!(None, parse_header(&[0; 20]));
+        assert_eq!(None, parse_header(&[[0; 20].as_ref(), &[65], &[4; 64]].concat()));
+
+        let header = parse_header(&[[1; 16].as_ref(), &[0, 0, 16, 0], &[0]].concat()).unwrap();
+
+        assert_eq!([1; 16], header.salt);
+        assert_eq!(4096, header.record_size);
+        assert!(header.key_id.is_empty());
+    }
+
+    #[test]
+    fn test_padding() {
+        let p256dh = base64::decode_config(P256DH, URL_SAFE).unwrap();
+        let auth = base64::decode_config(AUTH, URL_SAFE).unwrap();
+
+        let size = |encoding, padding, content: &[u8]| {
+            let mut http_ece = HttpEce::new(encoding, &p256dh, &auth, None);
+            http_ece.set_padding(padding);
+            http_ece.encrypt(content).map(|payload| payload.content.len())
+        };
+
+        //Header, content, delimiter and tag.
+        assert_eq!(
+            Ok(86 + 4 + 1 + 16),
+            size(ContentEncoding::Aes128Gcm, Padding::Minimal, b"test")
+        );
+        assert_eq!(
+            Ok(86 + 128 + 16),
+            size(ContentEncoding::Aes128Gcm, Padding::Block, b"test")
+        );
+        //Padding length, content and tag.
+        assert_eq!(Ok(2 + 4 + 16), size(ContentEncoding::AesGcm, Padding::Minimal, b"test"));
+
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            assert_eq!(
+                size(encoding, Padding::PadTo(1000), b"test"),
+                size(encoding, Padding::PadTo(1000), &[0u8; 1000])
+            );
+            assert_eq!(
+                Err(WebPushError::PayloadTooLarge),
+                size(encoding, Padding::PadTo(3), b"test")
+            );
+            assert_eq!(
+                Err(WebPushError::PayloadTooLarge),
+                size(encoding, Padding::PadTo(4090), b"test")
+            );
+            assert_eq!(
+                Err(WebPushError::PayloadTooLarge),
+                size(encoding, Padding::PadTo(usize::MAX), b"test")
+            );
+        }
     }
 }
@@ -42,24 +42,37 @@
 //! # }
 //! ```
 
+//Without a client, the helpers shared by the clients are unused.
+#![cfg_attr(not(any(feature = "isahc", feature = "hyper-client")), allow(dead_code))]
+
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate serde_derive;
 
 #[cfg(feature = "hyper-client")]
-pub use crate::clients::hyper_client::WebPushClient;
-#[cfg(not(feature = "hyper-client"))]
-pub use crate::clients::isahc_client::WebPushClient;
+pub use crate::clients::hyper_client::{WebPushClient, WebPushClientBuilder};
+#[cfg(all(feature = "isahc", not(feature = "hyper-client")))]
+pub use crate::clients::isahc_client::{HttpVersion, WebPushClient, WebPushClientBuilder};
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::clients::mock;
 pub use crate::clients::request_builder;
-pub use crate::error::WebPushError;
-pub use crate::http_ece::ContentEncoding;
-pub use crate::message::{SubscriptionInfo, SubscriptionKeys, WebPushMessage, WebPushMessageBuilder, WebPushPayload};
-pub use crate::vapid::builder::PartialVapidSignatureBuilder;
-pub use crate::vapid::{VapidSignature, VapidSignatureBuilder};
+pub use crate::clients::request_builder::WebPushResponse;
+pub use crate::clients::{RedirectPolicy, RetryConfig, SendEvent, SendObserver};
+pub use crate::error::{ErrorSource, WebPushError, WebPushErrorKind};
+pub use crate::http_ece::{ContentEncoding, Padding};
+pub use crate::message::{
+    NotificationBuilder, SubscriptionInfo, SubscriptionKeys, Urgency, WebPushMessage, WebPushMessageBuilder,
+    WebPushMessageTemplate, WebPushPayload,
+};
+pub use crate::vapid::builder::{PartialVapidSignatureBuilder, VapidKeyPair};
+pub use crate::vapid::{VapidKeyring, VapidSignature, VapidSignatureBuilder};
 
 mod clients;
 mod error;
-mod http_ece;
+#[cfg(test)]
+mod fixtures;
+pub mod http_ece;
 mod message;
+mod secret;
 mod vapid;
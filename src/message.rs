@@ -1,7 +1,14 @@
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use http::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE};
 use http::uri::Uri;
 
-use crate::error::WebPushError;
-use crate::http_ece::{ContentEncoding, HttpEce};
+use crate::error::{ErrorSource, WebPushError};
+use crate::http_ece::{ContentEncoding, HttpEce, Padding, MAX_PAYLOAD_SIZE, RECORD_SIZE};
+use crate::secret::Secret;
 use crate::vapid::VapidSignature;
 
 /// Encryption keys from the client.
@@ -17,6 +24,14 @@ pub struct SubscriptionKeys {
 /// subscription info JSON data (AKA pushSubscription object).
 ///
 /// Client pushSubscription objects can be directly deserialized into this struct using serde.
+///
+/// ```
+/// # use web_push::SubscriptionInfo;
+/// //The result of `PushSubscription.toJSON()` in the browser.
+/// let json = r#"{"endpoint":"https://updates.push.services.mozilla.com/wpush/v2/...","keys":{"p256dh":"...","auth":"..."}}"#;
+///
+/// let info: SubscriptionInfo = serde_json::from_str(json).unwrap();
+/// ```
 #[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq, Ord, PartialOrd, Default, Hash)]
 pub struct SubscriptionInfo {
     /// The endpoint URI for sending the notification.
@@ -40,10 +55,35 @@ impl SubscriptionInfo {
             },
         }
     }
+
+    /// Creates a `SubscriptionInfo` from keys that are already decoded, like keys stored as bytes in a database.
+    /// The keys are encoded to base64 once here, instead of by the caller before every send.
+    ///
+    /// The checks are the same as in [`WebPushMessageBuilder::new`]: fails with [`WebPushError::InvalidUri`] if the
+    /// endpoint is not an absolute `https` URL, and with [`WebPushError::InvalidCryptoKeys`] if `p256dh` is not
+    /// 65 bytes or `auth` is not 16 bytes.
+    pub fn from_bytes<S>(endpoint: S, p256dh: &[u8], auth: &[u8]) -> Result<SubscriptionInfo, WebPushError>
+    where
+        S: Into<String>,
+    {
+        let endpoint = endpoint.into();
+
+        parse_endpoint(&endpoint)?;
+        check_key_len("p256dh", p256dh, 65)?;
+        check_key_len("auth", auth, 16)?;
+
+        Ok(SubscriptionInfo {
+            endpoint,
+            keys: SubscriptionKeys {
+                p256dh: base64::encode_config(p256dh, base64::URL_SAFE_NO_PAD),
+                auth: base64::encode_config(auth, base64::URL_SAFE_NO_PAD),
+            },
+        })
+    }
 }
 
 /// The push content payload, already in an encrypted form.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct WebPushPayload {
     /// Encrypted content data.
     pub content: Vec<u8>,
@@ -53,29 +93,383 @@ pub struct WebPushPayload {
     pub content_encoding: &'static str,
 }
 
+/// How urgently the push service should deliver the message. Lower urgencies let the device save battery by
+/// delaying delivery until it wakes up for other reasons.
+#[derive(Debug, Eq, PartialEq, Copy, Clone, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Urgency {
+    VeryLow,
+    Low,
+    Normal,
+    High,
+}
+
+impl Urgency {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Urgency::VeryLow => "very-low",
+            Urgency::Low => "low",
+            Urgency::Normal => "normal",
+            Urgency::High => "high",
+        }
+    }
+}
+
+impl fmt::Display for Urgency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// The header of [`WebPushMessageBuilder::set_message_key`].
+const MESSAGE_KEY_HEADER: &str = "idempotency-key";
+
+/// The header of [`WebPushMessageBuilder::set_respond_async`].
+const PREFER_HEADER: &str = "prefer";
+
+/// Headers set by the protocol, which can't be replaced with [`WebPushMessageBuilder::add_header`].
+const PROTECTED_HEADERS: [&str; 10] = [
+    "authorization",
+    "content-encoding",
+    "content-length",
+    "content-type",
+    "crypto-key",
+    "encryption",
+    "host",
+    "topic",
+    "ttl",
+    "urgency",
+];
+
 /// Everything needed to send a push notification to the user.
-#[derive(Debug)]
+///
+/// The `Debug` output leaves out the encrypted content and the VAPID token, showing only the encoding and size
+/// of the payload, and the VAPID public key.
+///
+/// Messages can be serialized with serde, for building them in one process and sending them from another, like a
+/// worker reading from a queue. The payload is already encrypted for the subscription, so the worker only has to send
+/// it. Note that the VAPID token expires, 12 hours after signing by default. A message that waits in the queue for
+/// longer than that is rejected by the push service with [`WebPushError::Unauthorized`], and has to be built again
+/// with a new signature.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "SerializedMessage", into = "SerializedMessage")]
 pub struct WebPushMessage {
     /// The endpoint URI where to send the payload.
     pub endpoint: Uri,
     /// Time to live, how long the message should wait in the server if user is
     /// not online. Some services require this value to be set.
     pub ttl: u32,
-    /// The encrypted request payload, if sending any data.
+    /// The urgency of the message. If not set, the push service default is used.
+    pub urgency: Option<Urgency>,
+    /// Messages with the same topic replace each other while waiting to be delivered.
+    pub topic: Option<String>,
+    /// The encrypted request payload, if sending any data. Without a payload, the push only wakes up the
+    /// service worker, with no encryption headers sent.
     pub payload: Option<WebPushPayload>,
+    /// The VAPID signature, sent in the `Authorization` header with or without a payload.
+    pub vapid_signature: Option<VapidSignature>,
+    /// Custom headers to send along, like the API key of a proxy. Protocol headers always take precedence.
+    pub extra_headers: HeaderMap,
+}
+
+impl WebPushMessage {
+    /// The request body, with the payload already encrypted. Empty if the message has no payload.
+    pub fn body(&self) -> &[u8] {
+        match &self.payload {
+            Some(payload) => &payload.content,
+            None => &[],
+        }
+    }
+
+    /// The size of the request body in bytes, after encryption. This is the `Content-Length` of the request.
+    pub fn payload_len(&self) -> usize {
+        self.body().len()
+    }
+
+    /// The key set with [`WebPushMessageBuilder::set_message_key`], if any.
+    pub fn message_key(&self) -> Option<&str> {
+        self.extra_headers
+            .get(MESSAGE_KEY_HEADER)
+            .and_then(|value| value.to_str().ok())
+    }
+
+    /// Whether a VAPID signature is attached to the message.
+    pub fn has_vapid(&self) -> bool {
+        self.vapid_signature.is_some()
+    }
+
+    /// The value of the `Authorization` header, if a VAPID signature is attached. The `t` parameter is the signed
+    /// token, and `k` the public key, both base64 encoded.
+    pub fn authorization(&self) -> Option<String> {
+        self.vapid_signature.as_ref().map(|signature| {
+            format!(
+                "vapid t={}, k={}",
+                signature.auth_t,
+                base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD)
+            )
+        })
+    }
+
+    /// All the headers to send with the [body](#method.body): TTL, urgency, topic, VAPID authorization, the
+    /// encryption headers of the payload and any custom headers.
+    ///
+    /// Together with the endpoint and the body, these make up the complete `POST` request. This allows sending
+    /// the message with some other HTTP client or service instead of a `WebPushClient`.
+    pub fn headers(&self) -> HeaderMap {
+        //The protocol headers are inserted after the custom ones, replacing any with the same name.
+        let mut headers = self.extra_headers.clone();
+
+        //Custom headers may carry credentials, like the API key of a proxy, so they are kept out of debug output.
+        for value in headers.values_mut() {
+            value.set_sensitive(true);
+        }
+
+        headers.insert("ttl", HeaderValue::from(self.ttl));
+
+        if let Some(urgency) = self.urgency {
+            headers.insert("urgency", HeaderValue::from_static(urgency.as_str()));
+        }
+
+        //The fields are public, so a value that can't be sent is left out here instead of panicking. Building and
+        //deserializing a message reject such values.
+        if let Some(topic) = &self.topic {
+            insert_header(&mut headers, "topic", topic);
+        }
+
+        if let Some(authorization) = self.authorization() {
+            insert_header(&mut headers, AUTHORIZATION.as_str(), &authorization);
+        }
+
+        if let Some(payload) = &self.payload {
+            headers.insert(CONTENT_ENCODING, HeaderValue::from_static(payload.content_encoding));
+            headers.insert(CONTENT_LENGTH, HeaderValue::from(payload.content.len()));
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+
+            for (name, value) in &payload.crypto_headers {
+                insert_header(&mut headers, name, value);
+            }
+        }
+
+        headers
+    }
+
+    /// Checks the message without sending it: the endpoint must be an absolute `https` URL, the body at most 4096
+    /// bytes, all headers valid and the VAPID signature, if any, not expired. Fails with the error the builder or
+    /// the push service would give, like [`WebPushError::PayloadTooLarge`], or [`WebPushError::InvalidClaims`] for
+    /// an expired signature.
+    ///
+    /// Messages from [`WebPushMessageBuilder::build`] pass, until their signature expires. This is meant for
+    /// messages that were changed or deserialized, or for checking a configuration before sending anything.
+    pub fn validate(&self) -> Result<(), WebPushError> {
+        parse_endpoint(&self.endpoint.to_string())?;
+
+        if self.payload_len() > MAX_PAYLOAD_SIZE {
+            return Err(WebPushError::PayloadTooLarge);
+        }
+
+        let time_remaining = self.vapid_signature.as_ref().and_then(|s| s.time_remaining());
+
+        if time_remaining == Some(std::time::Duration::ZERO) {
+            return Err(WebPushError::InvalidClaims);
+        }
+
+        self.validate_headers()
+    }
+
+    /// Checks that all the headers given by the fields can be sent, see [`WebPushMessage::headers`].
+    fn validate_headers(&self) -> Result<(), WebPushError> {
+        if let Some(topic) = &self.topic {
+            validate_topic(topic)?;
+        }
+
+        if let Some(authorization) = self.authorization() {
+            HeaderValue::from_str(&authorization)
+                .map_err(|_| WebPushError::InvalidHeader(AUTHORIZATION.to_string()))?;
+        }
+
+        for (name, value) in self.payload.iter().flat_map(|payload| &payload.crypto_headers) {
+            HeaderValue::from_str(value).map_err(|_| WebPushError::InvalidHeader(name.to_string()))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Inserts a protocol header, leaving it out if it is not a valid header. The `Authorization` header is marked as
+/// sensitive, keeping the VAPID token out of debug output.
+fn insert_header(headers: &mut HeaderMap, name: &str, value: &str) {
+    match (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(value)) {
+        (Ok(name), Ok(mut value)) => {
+            value.set_sensitive(name == AUTHORIZATION);
+            headers.insert(name, value);
+        }
+        _ => debug!("Leaving out the invalid {} header", name),
+    }
+}
+
+/// The topic must be at most 32 characters from the URL-safe base64 alphabet.
+fn validate_topic(topic: &str) -> Result<(), WebPushError> {
+    let url_safe = topic
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+
+    if topic.is_empty() || topic.len() > 32 || !url_safe {
+        return Err(WebPushError::InvalidTopic);
+    }
+
+    Ok(())
+}
+
+/// The serialized form of a [`WebPushMessage`], with the endpoint and headers as strings and the encrypted content
+/// base64 encoded.
+#[derive(Deserialize, Serialize)]
+struct SerializedMessage {
+    endpoint: String,
+    ttl: u32,
+    urgency: Option<Urgency>,
+    topic: Option<String>,
+    payload: Option<SerializedPayload>,
+    vapid_signature: Option<VapidSignature>,
+    extra_headers: Vec<(String, String)>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SerializedPayload {
+    content: String,
+    crypto_headers: Vec<(String, String)>,
+    content_encoding: String,
+}
+
+impl From<WebPushMessage> for SerializedMessage {
+    fn from(message: WebPushMessage) -> Self {
+        SerializedMessage {
+            endpoint: message.endpoint.to_string(),
+            ttl: message.ttl,
+            urgency: message.urgency,
+            topic: message.topic,
+            payload: message.payload.map(|payload| SerializedPayload {
+                content: base64::encode_config(&payload.content, base64::URL_SAFE_NO_PAD),
+                crypto_headers: payload
+                    .crypto_headers
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), value))
+                    .collect(),
+                content_encoding: payload.content_encoding.to_string(),
+            }),
+            vapid_signature: message.vapid_signature,
+            //Custom header values are set from strings, so they are always UTF-8.
+            extra_headers: message
+                .extra_headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<SerializedMessage> for WebPushMessage {
+    type Error = WebPushError;
+
+    fn try_from(message: SerializedMessage) -> Result<Self, Self::Error> {
+        let payload = match message.payload {
+            Some(payload) => {
+                let content = base64::decode_config(&payload.content, base64::URL_SAFE_NO_PAD)
+                    .map_err(|_| WebPushError::Other(String::from("payload content is not valid base64")))?;
+                let content_encoding = ContentEncoding::from_str(&payload.content_encoding)?.to_str();
+
+                //The header names are static, so only the ones the encryption sets can be restored.
+                let crypto_headers = payload
+                    .crypto_headers
+                    .into_iter()
+                    .map(|(name, value)| match name.as_str() {
+                        "Crypto-Key" => Ok(("Crypto-Key", value)),
+                        "Encryption" => Ok(("Encryption", value)),
+                        _ => Err(WebPushError::InvalidHeader(name)),
+                    })
+                    .collect::<Result<_, _>>()?;
+
+                Some(WebPushPayload {
+                    content,
+                    crypto_headers,
+                    content_encoding,
+                })
+            }
+            None => None,
+        };
+
+        let mut extra_headers = HeaderMap::new();
+
+        for (name, value) in message.extra_headers {
+            let header_name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|_| WebPushError::InvalidHeader(name.clone()))?;
+            let header_value = HeaderValue::from_str(&value).map_err(|_| WebPushError::InvalidHeader(name))?;
+
+            extra_headers.append(header_name, header_value);
+        }
+
+        let message = WebPushMessage {
+            endpoint: parse_endpoint(&message.endpoint)?,
+            ttl: message.ttl,
+            urgency: message.urgency,
+            topic: message.topic,
+            payload,
+            vapid_signature: message.vapid_signature,
+            extra_headers,
+        };
+
+        message.validate_headers()?;
+
+        Ok(message)
+    }
+}
+
+impl fmt::Debug for WebPushMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WebPushMessage")
+            .field("endpoint", &self.endpoint)
+            .field("ttl", &self.ttl)
+            .field("urgency", &self.urgency)
+            .field("topic", &self.topic)
+            .field("content_encoding", &self.payload.as_ref().map(|p| p.content_encoding))
+            .field("payload_len", &self.payload_len())
+            .field(
+                "authorization",
+                &self.vapid_signature.as_ref().map(|signature| {
+                    format!(
+                        "vapid t=<redacted>, k={}",
+                        base64::encode_config(&signature.auth_k, base64::URL_SAFE_NO_PAD)
+                    )
+                }),
+            )
+            //Custom headers may carry credentials, so only their names are shown.
+            .field("extra_headers", &self.extra_headers.keys().collect::<Vec<_>>())
+            .finish()
+    }
 }
 
 struct WebPushPayloadBuilder<'a> {
-    pub content: &'a [u8],
+    pub content: Cow<'a, [u8]>,
     pub encoding: ContentEncoding,
 }
 
 /// The main class for creating a notification payload.
 pub struct WebPushMessageBuilder<'a> {
-    subscription_info: &'a SubscriptionInfo,
+    endpoint: Uri,
+    p256dh: Vec<u8>,
+    auth: Vec<u8>,
     payload: Option<WebPushPayloadBuilder<'a>>,
     ttl: u32,
+    urgency: Option<Urgency>,
+    topic: Option<String>,
     vapid_signature: Option<VapidSignature>,
+    extra_headers: Vec<(String, String)>,
+    message_key: Option<String>,
+    respond_async: bool,
+    payload_too_large: bool,
+    padding: Padding,
+    record_size: u32,
+    salt: Option<[u8; 16]>,
+    local_private_key: Option<Secret<[u8; 32]>>,
 }
 
 impl<'a> WebPushMessageBuilder<'a> {
@@ -83,61 +477,1247 @@ impl<'a> WebPushMessageBuilder<'a> {
     ///
     /// All parameters are from the subscription info given by browser when
     /// subscribing to push notifications.
+    ///
+    /// Fails with [`WebPushError::InvalidUri`] if the endpoint is not an absolute `https` URL, and with
+    /// [`WebPushError::InvalidCryptoKeys`] if the `p256dh` key is not a 65 byte uncompressed public key or the
+    /// `auth` secret is not 16 bytes.
     pub fn new(subscription_info: &'a SubscriptionInfo) -> Result<WebPushMessageBuilder<'a>, WebPushError> {
         Ok(WebPushMessageBuilder {
-            subscription_info,
+            endpoint: parse_endpoint(&subscription_info.endpoint)?,
+            p256dh: decode_key("p256dh", &subscription_info.keys.p256dh, 65)?,
+            auth: decode_key("auth", &subscription_info.keys.auth, 16)?,
             ttl: 2_419_200,
+            urgency: None,
+            topic: None,
             payload: None,
             vapid_signature: None,
+            extra_headers: Vec::new(),
+            message_key: None,
+            respond_async: false,
+            payload_too_large: false,
+            padding: Padding::default(),
+            record_size: RECORD_SIZE,
+            salt: None,
+            local_private_key: None,
         })
     }
 
     /// How long the server should keep the message if it cannot be delivered
-    /// currently. If not set, the message is deleted immediately on failed
-    /// delivery.
+    /// currently, in seconds. If not set, the message is kept for four weeks.
+    ///
+    /// A TTL of 0 means the message is dropped if it cannot be delivered immediately.
     pub fn set_ttl(&mut self, ttl: u32) {
         self.ttl = ttl;
     }
 
+    /// Sets the urgency of the message, sent as the `Urgency` header. If not set, the header is omitted and
+    /// the push service uses its default, which is `normal`.
+    pub fn set_urgency(&mut self, urgency: Urgency) {
+        self.urgency = Some(urgency);
+    }
+
+    /// Sets the topic of the message, sent as the `Topic` header. A message waiting to be delivered is replaced
+    /// by a newer message with the same topic.
+    ///
+    /// The topic must be at most 32 characters from the URL-safe base64 alphabet, otherwise building fails
+    /// with [`WebPushError::InvalidTopic`].
+    pub fn set_topic(&mut self, topic: String) {
+        self.topic = Some(topic);
+    }
+
+    /// Adds a custom header to the request, for example an API key required by a proxy in front of the push
+    /// service. Adding the same header again sends it multiple times.
+    ///
+    /// Headers used by the protocol, like `Authorization`, `Content-Encoding`, `Encryption` or `TTL`, can't be
+    /// replaced. Building fails with [`WebPushError::InvalidHeader`] if given one of those, or an invalid name
+    /// or value.
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.extra_headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets a key identifying this message, sent as the `Idempotency-Key` header, so duplicate deliveries can be
+    /// recognized. The key is part of the built message, so every retry of a client sends the same key, as does a
+    /// message that was serialized and sent again after a restart.
+    ///
+    /// Push services don't act on this header themselves; it is meant for a proxy in front of the push service
+    /// that deduplicates requests. To replace a message that is still waiting to be delivered, use
+    /// [`WebPushMessageBuilder::set_topic`] instead. Building fails with [`WebPushError::InvalidHeader`] if the key
+    /// is not a valid header value.
+    pub fn set_message_key(&mut self, key: &str) {
+        self.message_key = Some(key.to_string());
+    }
+
+    /// Asks the push service to acknowledge the message before it is stored, with the `Prefer: respond-async`
+    /// header. Push services that support this answer with `202 Accepted` instead of `201 Created`, see
+    /// [`WebPushResponse::is_async`](crate::WebPushResponse::is_async). Both are a successful send.
+    pub fn set_respond_async(&mut self, respond_async: bool) {
+        self.respond_async = respond_async;
+    }
+
     /// Add a VAPID signature to the request. To be generated with the
-    /// [VapidSignatureBuilder](struct.VapidSignatureBuilder.html).
+    /// [VapidSignatureBuilder](struct.VapidSignatureBuilder.html). The signature is sent with or without a payload.
+    ///
+    /// VAPID is opt-in: without a signature the message has no `Authorization` header and nothing is signed. This
+    /// suits self-hosted push services that don't check VAPID, but the browser push services require it.
     pub fn set_vapid_signature(&mut self, vapid_signature: VapidSignature) {
         self.vapid_signature = Some(vapid_signature);
     }
 
-    /// If set, the client will get content in the notification. Has a maximum size of
-    /// 3800 characters.
+    /// If set, the client will get content in the notification. The encrypted content has a maximum size of
+    /// 4096 bytes, which is checked when building. Content too long to fit with any padding is not encrypted at
+    /// all, and building fails with [`WebPushError::PayloadTooLarge`] right away.
     ///
-    /// Currently, Aes128Gcm is the recommended and only encoding standard implemented.
+    /// Without a payload, an empty push is sent with no encryption headers. This only wakes up the service
+    /// worker, and is the cheapest push for the device.
+    ///
+    /// Aes128Gcm is the recommended encoding standard. AesGcm is only kept for push services that still require it.
+    /// [`ContentEncoding::Auto`] is Aes128Gcm.
     pub fn set_payload(&mut self, encoding: ContentEncoding, content: &'a [u8]) {
-        self.payload = Some(WebPushPayloadBuilder { content, encoding });
+        self.set_payload_content(encoding, Cow::Borrowed(content));
     }
 
-    /// Builds and if set, encrypts the payload. Any errors due to bad encryption will be
-    /// [`WebPushError::Unspecified`], meaning
-    /// something was wrong in the given public key or authentication.
-    /// You can further debug these issues by checking the API responses visible with
-    /// `log::trace` level.
-    pub fn build(self) -> Result<WebPushMessage, WebPushError> {
-        let endpoint: Uri = self.subscription_info.endpoint.parse()?;
+    /// Sets a payload of `content` serialized as JSON, the way most service workers read it with
+    /// `event.data.json()`. The size is checked when building, like with [`WebPushMessageBuilder::set_payload`].
+    /// Fails with [`WebPushError::Json`] if `content` can't be serialized, leaving the payload unchanged.
+    pub fn set_json_payload<T: serde::Serialize + ?Sized>(
+        &mut self,
+        encoding: ContentEncoding,
+        content: &T,
+    ) -> Result<(), WebPushError> {
+        let content = serde_json::to_vec(content).map_err(|err| WebPushError::Json(ErrorSource::new(err)))?;
 
-        if let Some(payload) = self.payload {
-            let p256dh = base64::decode_config(&self.subscription_info.keys.p256dh, base64::URL_SAFE)?;
-            let auth = base64::decode_config(&self.subscription_info.keys.auth, base64::URL_SAFE)?;
+        self.set_payload_content(encoding, Cow::Owned(content));
 
-            let http_ece = HttpEce::new(payload.encoding, &p256dh, &auth, self.vapid_signature);
+        Ok(())
+    }
 
-            Ok(WebPushMessage {
-                endpoint,
-                ttl: self.ttl,
-                payload: Some(http_ece.encrypt(payload.content)?),
-            })
+    fn set_payload_content(&mut self, encoding: ContentEncoding, content: Cow<'a, [u8]>) {
+        let encoding = encoding.resolve();
+
+        //Content that can't fit with any padding fails the build right away, without being encrypted.
+        self.payload_too_large = content.len() > encoding.max_content_len();
+
+        self.payload = if self.payload_too_large {
+            None
         } else {
-            Ok(WebPushMessage {
-                endpoint,
-                ttl: self.ttl,
-                payload: None,
+            Some(WebPushPayloadBuilder { content, encoding })
+        };
+    }
+
+    /// Sets a text payload, like a JSON string. This is the same as [`WebPushMessageBuilder::set_payload`] with
+    /// the UTF-8 bytes of the text, and gives the same ciphertext.
+    ///
+    /// The size limit counts bytes, not characters: with Aes128Gcm the text can be at most 3967 bytes of UTF-8,
+    /// and longer texts fail building with [`WebPushError::PayloadTooLarge`].
+    pub fn set_text_payload(&mut self, encoding: ContentEncoding, content: &'a str) {
+        self.set_payload(encoding, content.as_bytes());
+    }
+
+    /// Sets how much the payload is padded before encrypting, to hide its exact length. By default it is padded to
+    /// a multiple of 128 bytes. Building fails with [`WebPushError::PayloadTooLarge`] if the padded payload is
+    /// too large, or the payload is longer than the length of [`Padding::PadTo`].
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    /// Sets the aes128gcm record size, 4096 bytes by default. The content is split into records of this size,
+    /// each with its own authentication tag, so smaller records make a larger message. Building fails with
+    /// [`WebPushError::InvalidRecordSize`] if the record size is less than 18 bytes. Has no effect with aesgcm,
+    /// which always uses a single record.
+    ///
+    /// **Warning:** [RFC 8291, section 4](https://datatracker.ietf.org/doc/html/rfc8291#section-4) requires push
+    /// messages to be a single record, and user agents following it may reject a message split into several. A
+    /// record size that can't hold the padded payload should only be used with a push service known to accept it.
+    /// The default always gives a single record.
+    pub fn set_record_size(&mut self, record_size: u32) {
+        self.record_size = record_size;
+    }
+
+    /// Encrypts the payload with a fixed salt instead of a random one. Together with
+    /// [`WebPushMessageBuilder::set_local_private_key`], this makes the encrypted payload the same on every build,
+    /// for asserting on the exact bytes in tests.
+    ///
+    /// Only available with the `test-util` feature. Never use this in production: reusing the salt and key
+    /// breaks the security of the encryption.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_salt(&mut self, salt: [u8; 16]) {
+        self.salt = Some(salt);
+    }
+
+    /// Encrypts the payload with a fixed local private key, the raw 32 byte P-256 scalar, instead of a random
+    /// ephemeral key. See [`WebPushMessageBuilder::set_salt`].
+    ///
+    /// Only available with the `test-util` feature. Never use this in production.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn set_local_private_key(&mut self, private_key: [u8; 32]) {
+        self.local_private_key = Some(Secret::new(private_key));
+    }
+
+    /// Builds and if set, encrypts the payload.
+    ///
+    /// Fails with [`WebPushError::InvalidCryptoKeys`] if the payload can't be encrypted for the `p256dh` key and
+    /// `auth` secret of the subscription, with [`WebPushError::PayloadTooLarge`] if the encrypted payload is over
+    /// [`MAX_PAYLOAD_SIZE`], and with [`WebPushError::InvalidRecordSize`] for a record size under 18 bytes. An
+    /// invalid topic fails with [`WebPushError::InvalidTopic`], and an invalid custom header or message key with
+    /// [`WebPushError::InvalidHeader`].
+    pub fn build(self) -> Result<WebPushMessage, WebPushError> {
+        if self.payload_too_large {
+            return Err(WebPushError::PayloadTooLarge);
+        }
+
+        if let Some(topic) = &self.topic {
+            validate_topic(topic)?;
+        }
+
+        let mut extra_headers = parse_extra_headers(&self.extra_headers)?;
+
+        if let Some(key) = &self.message_key {
+            let header_value = HeaderValue::from_str(key)
+                .map_err(|_| WebPushError::InvalidHeader(String::from(MESSAGE_KEY_HEADER)))?;
+
+            extra_headers.insert(MESSAGE_KEY_HEADER, header_value);
+        }
+
+        if self.respond_async {
+            extra_headers.insert(PREFER_HEADER, HeaderValue::from_static("respond-async"));
+        }
+
+        let payload = match self.payload {
+            Some(payload) => {
+                #[cfg(feature = "tracing")]
+                if payload.encoding.is_deprecated() {
+                    tracing::warn!(encoding = %payload.encoding, "Building a message with a deprecated content encoding");
+                }
+
+                let mut http_ece =
+                    HttpEce::new(payload.encoding, &self.p256dh, &self.auth, self.vapid_signature.clone());
+                http_ece.set_padding(self.padding);
+                http_ece.set_record_size(self.record_size);
+
+                if let Some(salt) = self.salt {
+                    http_ece.set_salt(salt);
+                }
+
+                if let Some(private_key) = self.local_private_key {
+                    http_ece.set_local_private_key(private_key);
+                }
+
+                Some(http_ece.encrypt(&payload.content)?)
+            }
+            None => None,
+        };
+
+        Ok(WebPushMessage {
+            endpoint: self.endpoint,
+            ttl: self.ttl,
+            urgency: self.urgency,
+            topic: self.topic,
+            payload,
+            vapid_signature: self.vapid_signature,
+            extra_headers,
+        })
+    }
+}
+
+/// The parts of a message that are the same for every recipient of a broadcast: the payload, TTL, urgency, topic
+/// and headers. The payload is encrypted separately for each subscription when building.
+///
+/// ```no_run
+/// # use web_push::*;
+/// # fn main() -> Result<(), WebPushError> {
+/// # let subscriptions: Vec<SubscriptionInfo> = vec![];
+/// # let vapid_builder = VapidSignatureBuilder::from_pem_no_sub("Some PEM".as_bytes())?;
+/// let mut template = WebPushMessageTemplate::new();
+/// template.set_payload(ContentEncoding::Aes128Gcm, b"Broadcast!");
+/// template.set_ttl(3600);
+///
+/// for subscription in &subscriptions {
+///     let signature = vapid_builder.clone().add_sub_info(subscription).build()?;
+///     let message = template.build(subscription, Some(signature))?;
+///     //Send the message.
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct WebPushMessageTemplate {
+    payload: Option<(ContentEncoding, Vec<u8>)>,
+    ttl: u32,
+    urgency: Option<Urgency>,
+    topic: Option<String>,
+    extra_headers: Vec<(String, String)>,
+    padding: Padding,
+    record_size: u32,
+}
+
+impl Default for WebPushMessageTemplate {
+    fn default() -> Self {
+        WebPushMessageTemplate {
+            payload: None,
+            ttl: 2_419_200,
+            urgency: None,
+            topic: None,
+            extra_headers: Vec::new(),
+            padding: Padding::default(),
+            record_size: RECORD_SIZE,
+        }
+    }
+}
+
+impl WebPushMessageTemplate {
+    /// Creates an empty template, like a new [`WebPushMessageBuilder`].
+    pub fn new() -> WebPushMessageTemplate {
+        WebPushMessageTemplate::default()
+    }
+
+    /// See [`WebPushMessageBuilder::set_ttl`].
+    pub fn set_ttl(&mut self, ttl: u32) {
+        self.ttl = ttl;
+    }
+
+    /// See [`WebPushMessageBuilder::set_urgency`].
+    pub fn set_urgency(&mut self, urgency: Urgency) {
+        self.urgency = Some(urgency);
+    }
+
+    /// See [`WebPushMessageBuilder::set_topic`].
+    pub fn set_topic(&mut self, topic: String) {
+        self.topic = Some(topic);
+    }
+
+    /// See [`WebPushMessageBuilder::add_header`].
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.extra_headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets the plaintext payload, see [`WebPushMessageBuilder::set_payload`].
+    pub fn set_payload(&mut self, encoding: ContentEncoding, content: &[u8]) {
+        self.payload = Some((encoding, content.to_vec()));
+    }
+
+    /// See [`WebPushMessageBuilder::set_padding`].
+    pub fn set_padding(&mut self, padding: Padding) {
+        self.padding = padding;
+    }
+
+    /// See [`WebPushMessageBuilder::set_record_size`].
+    pub fn set_record_size(&mut self, record_size: u32) {
+        self.record_size = record_size;
+    }
+
+    /// Builds the message for one subscription, encrypting the payload for it. Fails like
+    /// [`WebPushMessageBuilder::new`] and [`WebPushMessageBuilder::build`].
+    pub fn build(
+        &self,
+        subscription_info: &SubscriptionInfo,
+        vapid_signature: Option<VapidSignature>,
+    ) -> Result<WebPushMessage, WebPushError> {
+        let mut builder = WebPushMessageBuilder::new(subscription_info)?;
+
+        builder.set_ttl(self.ttl);
+        builder.set_padding(self.padding);
+        builder.set_record_size(self.record_size);
+
+        if let Some(urgency) = self.urgency {
+            builder.set_urgency(urgency);
+        }
+
+        if let Some(topic) = &self.topic {
+            builder.set_topic(topic.clone());
+        }
+
+        for (name, value) in &self.extra_headers {
+            builder.add_header(name, value);
+        }
+
+        if let Some((encoding, content)) = &self.payload {
+            builder.set_payload(*encoding, content);
+        }
+
+        if let Some(vapid_signature) = vapid_signature {
+            builder.set_vapid_signature(vapid_signature);
+        }
+
+        builder.build()
+    }
+}
+
+/// An action button shown on a notification.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct NotificationAction {
+    action: String,
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+}
+
+/// Builds a payload with the usual JSON shape for notifications: the title together with the fields of
+/// the `options` of `showNotification`. The service worker can then show it with
+/// `self.registration.showNotification(data.title, data)`.
+///
+/// This is optional, any bytes can be sent as the payload.
+///
+/// ```
+/// # use web_push::*;
+/// # fn main() -> Result<(), WebPushError> {
+/// # let subscription_info = SubscriptionInfo::new(
+/// #     "https://updates.push.services.mozilla.com/wpush/v2/...",
+/// #     "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+/// #     "xS03Fi5ErfTNH_l9WHE9Ig",
+/// # );
+/// let mut notification = NotificationBuilder::new("New message");
+/// notification.set_body("Hello there!");
+/// notification.add_action("reply", "Reply");
+///
+/// let payload = notification.build();
+///
+/// let mut builder = WebPushMessageBuilder::new(&subscription_info)?;
+/// builder.set_payload(ContentEncoding::Aes128Gcm, &payload);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationBuilder {
+    title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    badge: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    require_interaction: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    silent: Option<bool>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    actions: Vec<NotificationAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+}
+
+impl NotificationBuilder {
+    /// Creates a builder for a notification with the given title.
+    pub fn new<S: Into<String>>(title: S) -> NotificationBuilder {
+        NotificationBuilder {
+            title: title.into(),
+            body: None,
+            icon: None,
+            badge: None,
+            image: None,
+            tag: None,
+            require_interaction: None,
+            silent: None,
+            actions: Vec::new(),
+            data: None,
+        }
+    }
+
+    /// The text shown below the title.
+    pub fn set_body<S: Into<String>>(&mut self, body: S) {
+        self.body = Some(body.into());
+    }
+
+    /// The URL of the icon shown with the notification.
+    pub fn set_icon<S: Into<String>>(&mut self, icon: S) {
+        self.icon = Some(icon.into());
+    }
+
+    /// The URL of the small monochrome image shown where there isn't room for the whole notification.
+    pub fn set_badge<S: Into<String>>(&mut self, badge: S) {
+        self.badge = Some(badge.into());
+    }
+
+    /// The URL of a larger image shown in the notification.
+    pub fn set_image<S: Into<String>>(&mut self, image: S) {
+        self.image = Some(image.into());
+    }
+
+    /// Notifications with the same tag replace each other on the device.
+    pub fn set_tag<S: Into<String>>(&mut self, tag: S) {
+        self.tag = Some(tag.into());
+    }
+
+    /// Whether the notification stays until the user dismisses or clicks it.
+    pub fn set_require_interaction(&mut self, require_interaction: bool) {
+        self.require_interaction = Some(require_interaction);
+    }
+
+    /// Whether the notification is shown without sound or vibration.
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = Some(silent);
+    }
+
+    /// Adds an action button. `action` identifies the button in the `notificationclick` event, and `title` is
+    /// shown to the user.
+    pub fn add_action<S: Into<String>>(&mut self, action: S, title: S) {
+        self.actions.push(NotificationAction {
+            action: action.into(),
+            title: title.into(),
+            icon: None,
+        });
+    }
+
+    /// Adds an action button with an icon, like [`NotificationBuilder::add_action`].
+    pub fn add_action_with_icon<S: Into<String>>(&mut self, action: S, title: S, icon: S) {
+        self.actions.push(NotificationAction {
+            action: action.into(),
+            title: title.into(),
+            icon: Some(icon.into()),
+        });
+    }
+
+    /// Any extra data for the service worker, available as `notification.data`.
+    pub fn set_data<V: Into<serde_json::Value>>(&mut self, data: V) {
+        self.data = Some(data.into());
+    }
+
+    /// Serializes the notification to JSON, ready to be used with [`WebPushMessageBuilder::set_payload`].
+    pub fn build(&self) -> Vec<u8> {
+        //Only strings, booleans and JSON values, which can always be serialized.
+        serde_json::to_vec(self).unwrap_or_default()
+    }
+}
+
+/// Parses custom headers, see [`WebPushMessageBuilder::add_header`]. Fails with [`WebPushError::InvalidHeader`] for
+/// invalid names or values, and for headers used by the protocol.
+pub(crate) fn parse_extra_headers(headers: &[(String, String)]) -> Result<HeaderMap, WebPushError> {
+    let mut extra_headers = HeaderMap::new();
+
+    for (name, value) in headers {
+        let header_name =
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| WebPushError::InvalidHeader(name.clone()))?;
+        let header_value = HeaderValue::from_str(value).map_err(|_| WebPushError::InvalidHeader(name.clone()))?;
+
+        if PROTECTED_HEADERS.contains(&header_name.as_str()) {
+            return Err(WebPushError::InvalidHeader(name.clone()));
+        }
+
+        extra_headers.append(header_name, header_value);
+    }
+
+    Ok(extra_headers)
+}
+
+/// Parses a subscription endpoint, which must be an absolute `https` URL. With the `allow-http-localhost` feature,
+/// `http` is also accepted for `localhost`, `127.0.0.1` and `[::1]`, for push services running locally.
+pub(crate) fn parse_endpoint(endpoint: &str) -> Result<Uri, WebPushError> {
+    let uri: Uri = endpoint.parse()?;
+
+    match (uri.scheme_str(), uri.host()) {
+        (Some("https"), Some(_)) => Ok(uri),
+        (Some("http"), Some(host)) if cfg!(feature = "allow-http-localhost") && is_localhost(host) => Ok(uri),
+        (Some(scheme), Some(_)) => Err(WebPushError::InvalidUri(format!(
+            "endpoint must use https, but was {}",
+            scheme
+        ))),
+        _ => Err(WebPushError::InvalidUri(String::from(
+            "endpoint must be an absolute https URL",
+        ))),
+    }
+}
+
+fn is_localhost(host: &str) -> bool {
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// Decodes a base64 subscription key, checking it has the expected length.
+fn decode_key(name: &str, key: &str, expected_len: usize) -> Result<Vec<u8>, WebPushError> {
+    let decoded = base64::decode_config(key, base64::URL_SAFE)
+        .map_err(|_| WebPushError::InvalidCryptoKeys(Some(format!("{} is not valid base64", name))))?;
+
+    check_key_len(name, &decoded, expected_len)?;
+
+    Ok(decoded)
+}
+
+fn check_key_len(name: &str, key: &[u8], expected_len: usize) -> Result<(), WebPushError> {
+    if key.len() != expected_len {
+        return Err(WebPushError::InvalidCryptoKeys(Some(format!(
+            "{} should be {} bytes, but was {} bytes",
+            name,
+            expected_len,
+            key.len()
+        ))));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::WebPushError;
+    use crate::fixtures::{subscription_info, AUTH, ENDPOINT, P256DH};
+    use crate::http_ece::{ContentEncoding, Padding};
+    use crate::message::{
+        NotificationBuilder, SubscriptionInfo, Urgency, WebPushMessage, WebPushMessageBuilder, WebPushMessageTemplate,
+    };
+    use crate::vapid::VapidSignature;
+
+    #[test]
+    fn creates_subscriptions_from_decoded_keys() {
+        let info = subscription_info();
+        let p256dh = base64::decode_config(&info.keys.p256dh, base64::URL_SAFE).unwrap();
+        let auth = base64::decode_config(&info.keys.auth, base64::URL_SAFE).unwrap();
+
+        assert_eq!(
+            Ok(info.clone()),
+            SubscriptionInfo::from_bytes(info.endpoint.as_str(), &p256dh, &auth)
+        );
+
+        assert_eq!(
+            Err(WebPushError::InvalidCryptoKeys(Some(String::from(
+                "auth should be 16 bytes, but was 15 bytes"
+            )))),
+            SubscriptionInfo::from_bytes(info.endpoint.as_str(), &p256dh, &auth[1..])
+        );
+        assert!(matches!(
+            SubscriptionInfo::from_bytes("http://example.com/push", &p256dh, &auth),
+            Err(WebPushError::InvalidUri(_))
+        ));
+    }
+
+    #[test]
+    fn parses_browser_subscription_json() {
+        let json = r#"{
+            "endpoint": "https://updates.push.services.mozilla.com/wpush/v2/foo",
+            "expirationTime": null,
+            "keys": {
+                "p256dh": "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+                "auth": "xS03Fi5ErfTNH_l9WHE9Ig"
+            }
+        }"#;
+
+        let info: SubscriptionInfo = serde_json::from_str(json).unwrap();
+
+        assert_eq!(subscription_info(), info);
+    }
+
+    #[test]
+    fn round_trips_subscription_json() {
+        let json = serde_json::json!({
+            "endpoint": "https://updates.push.services.mozilla.com/wpush/v2/foo",
+            "keys": {
+                "p256dh": "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4yV8",
+                "auth": "xS03Fi5ErfTNH_l9WHE9Ig"
+            }
+        });
+
+        let info: SubscriptionInfo = serde_json::from_value(json.clone()).unwrap();
+
+        assert_eq!(json, serde_json::to_value(&info).unwrap());
+    }
+
+    #[test]
+    fn exposes_the_encrypted_body_and_headers() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_ttl(60);
+        builder.set_payload(ContentEncoding::AesGcm, b"test");
+
+        let message = builder.build().unwrap();
+        let headers = message.headers();
+
+        assert_eq!(message.payload.as_ref().unwrap().content, message.body());
+        assert_eq!("60", headers["TTL"]);
+        assert_eq!("aesgcm", headers["Content-Encoding"]);
+        assert_eq!(message.body().len().to_string(), headers["Content-Length"]);
+        assert!(headers.contains_key("Encryption"));
+        assert!(headers.contains_key("Crypto-Key"));
+    }
+
+    #[test]
+    fn has_an_empty_body_without_payload() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_ttl(60);
+        builder.set_vapid_signature(VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        });
+
+        let message = builder.build().unwrap();
+        let headers = message.headers();
+
+        assert!(message.body().is_empty());
+        assert_eq!("60", headers["TTL"]);
+        assert_eq!("vapid t=foo, k=YmFy", headers["Authorization"]);
+        assert!(headers["Authorization"].is_sensitive());
+        assert!(!headers["TTL"].is_sensitive());
+        assert!(!headers.contains_key("Content-Encoding"));
+        assert!(!headers.contains_key("Encryption"));
+        assert!(!headers.contains_key("Crypto-Key"));
+    }
+
+    #[test]
+    fn rejects_keys_with_a_wrong_length() {
+        let truncated_p256dh = SubscriptionInfo::new(
+            ENDPOINT,
+            "BLMbF9ffKBiWQLCKvTHb6LO8Nb6dcUh6TItC455vu2kElga6PQvUmaFyCdykxY2nOSSL3yKgfbmFLRTUaGv4",
+            AUTH,
+        );
+
+        assert_eq!(
+            Some(WebPushError::InvalidCryptoKeys(Some(
+                "p256dh should be 65 bytes, but was 63 bytes".to_string()
+            ))),
+            WebPushMessageBuilder::new(&truncated_p256dh).err()
+        );
+
+        let long_auth = SubscriptionInfo::new(ENDPOINT, P256DH, "xS03Fi5ErfTNH_l9WHE9IgAA");
+
+        assert_eq!(
+            Some(WebPushError::InvalidCryptoKeys(Some(
+                "auth should be 16 bytes, but was 18 bytes".to_string()
+            ))),
+            WebPushMessageBuilder::new(&long_auth).err()
+        );
+    }
+
+    #[test]
+    fn debug_output_hides_the_payload_and_signature() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_vapid_signature(VapidSignature {
+            auth_t: String::from("secret-token"),
+            auth_k: String::from("bar").into_bytes(),
+        });
+
+        let message = builder.build().unwrap();
+        let debug = format!("{:?}", message);
+
+        assert_eq!(230, message.payload_len());
+        assert!(debug.contains("content_encoding: Some(\"aes128gcm\")"));
+        assert!(debug.contains("payload_len: 230"));
+        assert!(debug.contains("vapid t=<redacted>, k=YmFy"));
+        assert!(!debug.contains("secret-token"));
+    }
+
+    #[test]
+    fn adds_custom_headers() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.add_header("X-Api-Key", "secret-key");
+
+        let message = builder.build().unwrap();
+
+        assert_eq!("secret-key", message.headers()["X-Api-Key"]);
+        assert!(!format!("{:?}", message).contains("secret-key"));
+        assert!(!format!("{:?}", message.headers()).contains("secret-key"));
+    }
+
+    #[test]
+    fn rejects_invalid_custom_headers() {
+        let info = subscription_info();
+
+        for (name, value) in [
+            ("Authorization", "Bearer foo"),
+            ("content-encoding", "gzip"),
+            ("Crypto-Key", "dh=foo"),
+            ("TTL", "0"),
+            ("Bad Name", "foo"),
+            ("X-Api-Key", "new\nline"),
+        ] {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+            builder.add_header(name, value);
+
+            assert_eq!(
+                Some(WebPushError::InvalidHeader(name.to_string())),
+                builder.build().err()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_endpoints_that_are_not_absolute_https_urls() {
+        for (endpoint, detail) in [
+            (
+                "http://updates.push.services.mozilla.com/wpush/v2/foo",
+                "endpoint must use https, but was http",
+            ),
+            (
+                "ftp://updates.push.services.mozilla.com/wpush/v2/foo",
+                "endpoint must use https, but was ftp",
+            ),
+            ("/wpush/v2/foo", "endpoint must be an absolute https URL"),
+            (
+                "updates.push.services.mozilla.com",
+                "endpoint must be an absolute https URL",
+            ),
+            ("not a url", "endpoint is not a valid URI: invalid uri character"),
+        ] {
+            let info = SubscriptionInfo::new(endpoint, P256DH, AUTH);
+
+            assert_eq!(
+                Some(WebPushError::InvalidUri(detail.to_string())),
+                WebPushMessageBuilder::new(&info).err()
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_http_on_localhost_only_with_the_feature() {
+        let info = SubscriptionInfo::new("http://localhost:8080/push/foo", P256DH, AUTH);
+
+        assert_eq!(
+            cfg!(feature = "allow-http-localhost"),
+            WebPushMessageBuilder::new(&info).is_ok()
+        );
+    }
+
+    #[test]
+    fn sends_no_authorization_without_vapid() {
+        let info = subscription_info();
+
+        for encoding in [None, Some(ContentEncoding::Aes128Gcm), Some(ContentEncoding::AesGcm)] {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+
+            if let Some(encoding) = encoding {
+                builder.set_payload(encoding, b"test");
+            }
+
+            let headers = builder.build().unwrap().headers();
+
+            assert!(!headers.contains_key("Authorization"));
+
+            if let Some(crypto_key) = headers.get("Crypto-Key") {
+                assert!(!crypto_key.to_str().unwrap().contains("p256ecdsa"));
+            }
+        }
+    }
+
+    #[test]
+    fn exposes_the_vapid_authorization() {
+        let info = subscription_info();
+
+        let message = WebPushMessageBuilder::new(&info).unwrap().build().unwrap();
+
+        assert!(!message.has_vapid());
+        assert_eq!(None, message.authorization());
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_vapid_signature(VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        });
+
+        let message = builder.build().unwrap();
+
+        assert!(message.has_vapid());
+        assert_eq!(Some(String::from("vapid t=foo, k=YmFy")), message.authorization());
+    }
+
+    #[test]
+    fn encrypts_deterministically_with_fixed_keys() {
+        let info = subscription_info();
+
+        for encoding in [ContentEncoding::Aes128Gcm, ContentEncoding::AesGcm] {
+            let build = || {
+                let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+                builder.set_payload(encoding, b"test");
+                builder.set_salt([1; 16]);
+                builder.set_local_private_key([2; 32]);
+                builder.build().unwrap()
+            };
+
+            let (first, second) = (build(), build());
+
+            assert_eq!(first.body(), second.body());
+            assert_eq!(first.headers(), second.headers());
+        }
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_salt([1; 16]);
+
+        assert_eq!(&[1; 16], &builder.build().unwrap().body()[..16]);
+    }
+
+    #[test]
+    fn encrypts_text_payloads_like_bytes() {
+        let info = subscription_info();
+        let text = r#"{"title":"Grüße"}"#;
+
+        let build = |text_payload: bool| {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+
+            if text_payload {
+                builder.set_text_payload(ContentEncoding::Aes128Gcm, text);
+            } else {
+                builder.set_payload(ContentEncoding::Aes128Gcm, text.as_bytes());
+            }
+
+            builder.set_salt([1; 16]);
+            builder.set_local_private_key([2; 32]);
+            builder.build()
+        };
+
+        assert_eq!(build(false).unwrap().body(), build(true).unwrap().body());
+
+        //Two bytes per character, so this is over the limit in bytes but not in characters.
+        let long_text = "ü".repeat(2000);
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_text_payload(ContentEncoding::Aes128Gcm, &long_text);
+
+        assert_eq!(Some(WebPushError::PayloadTooLarge), builder.build().err());
+    }
+
+    #[test]
+    fn encrypts_json_payloads_like_bytes() {
+        let info = subscription_info();
+
+        let build = |json_payload: bool| {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+
+            if json_payload {
+                builder
+                    .set_json_payload(ContentEncoding::Aes128Gcm, &serde_json::json!({ "title": "Hello" }))
+                    .unwrap();
+            } else {
+                builder.set_payload(ContentEncoding::Aes128Gcm, br#"{"title":"Hello"}"#);
+            }
+
+            builder.set_salt([1; 16]);
+            builder.set_local_private_key([2; 32]);
+            builder.build()
+        };
+
+        assert_eq!(build(false).unwrap().body(), build(true).unwrap().body());
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder
+            .set_json_payload(ContentEncoding::Aes128Gcm, &"a".repeat(4000))
+            .unwrap();
+
+        assert_eq!(Some(WebPushError::PayloadTooLarge), builder.build().err());
+
+        //JSON object keys must be strings.
+        let unserializable: std::collections::HashMap<(u8, u8), u8> = vec![((1, 2), 3)].into_iter().collect();
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+
+        assert!(matches!(
+            builder.set_json_payload(ContentEncoding::Aes128Gcm, &unserializable),
+            Err(WebPushError::Json(_))
+        ));
+        assert_eq!(0, builder.build().unwrap().payload_len());
+    }
+
+    #[test]
+    fn rejects_oversized_payloads_before_encrypting() {
+        let info = subscription_info();
+        let huge = vec![0; 4 * 1024 * 1024];
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, &huge);
+
+        assert_eq!(Some(WebPushError::PayloadTooLarge), builder.build().err());
+
+        //A later payload that fits replaces the oversized one.
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, &huge);
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        assert_eq!(230, builder.build().unwrap().payload_len());
+    }
+
+    #[test]
+    fn pads_to_a_fixed_length() {
+        let info = subscription_info();
+
+        let payload_len = |content: &'static [u8], padding| {
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+            builder.set_payload(ContentEncoding::Aes128Gcm, content);
+            builder.set_padding(padding);
+            builder.build().map(|message| message.payload_len())
+        };
+
+        assert_eq!(Ok(86 + 4 + 1 + 16), payload_len(b"test", Padding::Minimal));
+        assert_eq!(Ok(86 + 512 + 1 + 16), payload_len(b"test", Padding::PadTo(512)));
+        assert_eq!(Ok(86 + 512 + 1 + 16), payload_len(&[0; 500], Padding::PadTo(512)));
+        assert_eq!(
+            Err(WebPushError::PayloadTooLarge),
+            payload_len(&[0; 513], Padding::PadTo(512))
+        );
+    }
+
+    #[test]
+    fn asks_for_an_async_response() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.add_header("Prefer", "return=minimal");
+        builder.set_respond_async(true);
+
+        let headers = builder.build().unwrap().headers();
+
+        assert_eq!(
+            vec!["respond-async"],
+            headers.get_all("Prefer").iter().collect::<Vec<_>>()
+        );
+
+        let builder = WebPushMessageBuilder::new(&info).unwrap();
+
+        assert!(!builder.build().unwrap().headers().contains_key("Prefer"));
+    }
+
+    #[test]
+    fn auto_encoding_is_aes128gcm_for_every_endpoint() {
+        for (endpoint, expected) in [
+            (ENDPOINT, "aes128gcm"),
+            ("https://fcm.googleapis.com/fcm/send/foo", "aes128gcm"),
+            ("https://android.googleapis.com/gcm/send/foo", "aes128gcm"),
+        ] {
+            let info = SubscriptionInfo::new(endpoint, P256DH, AUTH);
+
+            let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+            builder.set_payload(ContentEncoding::Auto, b"test");
+
+            let message = builder.build().unwrap();
+
+            assert_eq!(Some(expected), message.payload.map(|p| p.content_encoding));
+        }
+    }
+
+    #[test]
+    fn sets_the_record_size() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_record_size(64);
+
+        let message = builder.build().unwrap();
+        let header = crate::http_ece::parse_header(message.body()).unwrap();
+
+        //The content and its padding take 127 bytes, in records holding 47 bytes each besides the delimiter and tag.
+        assert_eq!(64, header.record_size);
+        assert_eq!(86 + 2 * 64 + (127 - 2 * 47) + 1 + 16, message.body().len());
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        builder.set_record_size(17);
+
+        assert_eq!(Some(WebPushError::InvalidRecordSize(17)), builder.build().err());
+    }
+
+    #[test]
+    fn validates_messages() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+
+        let mut message = builder.build().unwrap();
+
+        assert_eq!(Ok(()), message.validate());
+
+        //A token that expired at the start of 1970.
+        let claims = base64::encode_config(br#"{"exp":1}"#, base64::URL_SAFE_NO_PAD);
+        message.vapid_signature = Some(VapidSignature {
+            auth_t: format!("e30.{}.c2ln", claims),
+            auth_k: String::from("bar").into_bytes(),
+        });
+
+        assert_eq!(Err(WebPushError::InvalidClaims), message.validate());
+
+        message.vapid_signature = None;
+        message.topic = Some(String::from("not a topic"));
+
+        assert_eq!(Err(WebPushError::InvalidTopic), message.validate());
+    }
+
+    #[test]
+    fn sets_the_message_key() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.add_header("Idempotency-Key", "replaced");
+        builder.set_message_key("order-42");
+
+        let message = builder.build().unwrap();
+
+        assert_eq!(Some("order-42"), message.message_key());
+        assert_eq!(1, message.headers().get_all("Idempotency-Key").iter().count());
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_message_key("line\nbreak");
+
+        assert_eq!(
+            Some(WebPushError::InvalidHeader(String::from("idempotency-key"))),
+            builder.build().err()
+        );
+    }
+
+    #[test]
+    fn serializes_messages() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::AesGcm, b"test");
+        builder.set_urgency(Urgency::VeryLow);
+        builder.add_header("X-Api-Key", "secret");
+        builder.set_vapid_signature(VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        });
+
+        let message = builder.build().unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+        let queued: WebPushMessage = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(message.endpoint, queued.endpoint);
+        assert_eq!(message.body(), queued.body());
+        assert_eq!(message.headers(), queued.headers());
+        assert_eq!(message.vapid_signature, queued.vapid_signature);
+
+        let tampered = json.replace("https://updates", "http://updates");
+
+        assert!(serde_json::from_str::<WebPushMessage>(&tampered).is_err());
+    }
+
+    #[test]
+    fn builds_messages_from_a_template() {
+        let first = subscription_info();
+        let second = SubscriptionInfo::new(
+            "https://fcm.googleapis.com/fcm/send/bar",
+            "BGa4N1PI79lboMR_YrwCiCsgp35DRvedt7opHcf0yM3iOBTSoQYqQLwWxAfRKE6tsDnReWmhsImkhDF_DBdkNSU",
+            "EvcWjEgzr4rbvhfi3yds0A",
+        );
+
+        let mut template = WebPushMessageTemplate::new();
+        template.set_payload(ContentEncoding::Aes128Gcm, b"test");
+        template.set_ttl(60);
+        template.set_urgency(Urgency::High);
+        template.set_topic(String::from("news"));
+        template.add_header("X-Api-Key", "secret");
+
+        let signature = VapidSignature {
+            auth_t: String::from("foo"),
+            auth_k: String::from("bar").into_bytes(),
+        };
+
+        let first = template.build(&first, Some(signature)).unwrap();
+        let second = template.build(&second, None).unwrap();
+
+        for message in [&first, &second] {
+            let headers = message.headers();
+
+            assert_eq!("60", headers["TTL"]);
+            assert_eq!("high", headers["Urgency"]);
+            assert_eq!("news", headers["Topic"]);
+            assert_eq!("secret", headers["X-Api-Key"]);
+            assert_eq!(230, message.payload_len());
+        }
+
+        assert_eq!("fcm.googleapis.com", second.endpoint.host().unwrap());
+        assert!(first.has_vapid());
+        assert!(!second.has_vapid());
+        assert_ne!(first.body(), second.body());
+    }
+
+    #[test]
+    fn builds_notification_json() {
+        let mut notification = NotificationBuilder::new("New message");
+        notification.set_body("Hello there!");
+        notification.set_icon("/icon.png");
+        notification.set_require_interaction(true);
+        notification.add_action("reply", "Reply");
+        notification.set_data(serde_json::json!({"url": "/messages/1"}));
+
+        let json: serde_json::Value = serde_json::from_slice(&notification.build()).unwrap();
+
+        assert_eq!(
+            serde_json::json!({
+                "title": "New message",
+                "body": "Hello there!",
+                "icon": "/icon.png",
+                "requireInteraction": true,
+                "actions": [{"action": "reply", "title": "Reply"}],
+                "data": {"url": "/messages/1"}
+            }),
+            json
+        );
+    }
+
+    /// Deterministic garbage strings mixing base64, URL and control characters.
+    fn garbage(seed: &mut u64, len: usize) -> String {
+        const CHARS: &[u8] = b"ABCxyz019-_+/=:.%?#@[] \r\n\t\x00\x7f\xc3\xa9";
+
+        (0..len)
+            .map(|_| {
+                *seed ^= *seed << 13;
+                *seed ^= *seed >> 7;
+                *seed ^= *seed << 17;
+                CHARS[(*seed % CHARS.len() as u64) as usize] as char
             })
+            .collect()
+    }
+
+    #[test]
+    fn handles_garbage_subscriptions_without_panicking() {
+        let mut seed = 0x2545_f491_4f6c_dd1d;
+
+        for len in 0..512 {
+            let endpoints = [
+                garbage(&mut seed, len % 64),
+                format!("https://{}", garbage(&mut seed, len % 64)),
+            ];
+
+            for endpoint in endpoints {
+                let info = SubscriptionInfo::new(endpoint, garbage(&mut seed, len % 100), garbage(&mut seed, len % 30));
+
+                if let Ok(mut builder) = WebPushMessageBuilder::new(&info) {
+                    builder.set_payload(ContentEncoding::Aes128Gcm, b"test");
+                    builder.set_topic(garbage(&mut seed, len % 40));
+                    builder.set_message_key(&garbage(&mut seed, len % 40));
+
+                    if let Ok(message) = builder.build() {
+                        message.headers();
+                    }
+                }
+            }
         }
     }
+
+    #[test]
+    fn rejects_garbage_when_deserializing_messages() {
+        let info = subscription_info();
+
+        let mut builder = WebPushMessageBuilder::new(&info).unwrap();
+        builder.set_payload(ContentEncoding::AesGcm, b"test");
+        builder.set_topic(String::from("news"));
+
+        let json: serde_json::Value = serde_json::to_value(builder.build().unwrap()).unwrap();
+        let mut seed = 0x9e37_79b9_7f4a_7c15;
+
+        //Replace each string in the message with garbage, anything that is let through must still produce headers.
+        for _ in 0..256 {
+            let mut tampered = json.clone();
+
+            tampered["topic"] = garbage(&mut seed, 8).into();
+            tampered["endpoint"] = format!("https://{}", garbage(&mut seed, 8)).into();
+
+            for header in tampered["payload"]["crypto_headers"].as_array_mut().unwrap() {
+                header[1] = garbage(&mut seed, 16).into();
+            }
+
+            if let Ok(message) = serde_json::from_value::<WebPushMessage>(tampered) {
+                message.headers();
+            }
+        }
+
+        let mut tampered = json;
+        tampered["topic"] = "line\nbreak".into();
+
+        assert!(serde_json::from_value::<WebPushMessage>(tampered).is_err());
+    }
 }
@@ -0,0 +1,65 @@
+//! Buffers for key material, like the VAPID private key and the secrets derived when encrypting.
+//!
+//! With the `zeroize` feature these are [`zeroize::Zeroizing`], which wipes them from memory when dropped.
+//! Without it, [`Secret`] is a plain wrapper with the same interface.
+
+#[cfg(feature = "zeroize")]
+pub(crate) type Secret<T> = zeroize::Zeroizing<T>;
+
+#[cfg(not(feature = "zeroize"))]
+pub(crate) use self::plain::Secret;
+
+#[cfg(not(feature = "zeroize"))]
+mod plain {
+    use std::ops::{Deref, DerefMut};
+
+    pub(crate) struct Secret<T>(T);
+
+    impl<T> Secret<T> {
+        pub(crate) fn new(value: T) -> Secret<T> {
+            Secret(value)
+        }
+    }
+
+    impl<T> Deref for Secret<T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.0
+        }
+    }
+
+    impl<T> DerefMut for Secret<T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.0
+        }
+    }
+}
+
+#[cfg(all(test, feature = "zeroize"))]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use zeroize::Zeroize;
+
+    use crate::secret::Secret;
+
+    struct Canary(Rc<Cell<bool>>);
+
+    impl Zeroize for Canary {
+        fn zeroize(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn wipes_secrets_on_drop() {
+        let wiped = Rc::new(Cell::new(false));
+        let secret = Secret::new(Canary(wiped.clone()));
+
+        assert!(!wiped.get());
+        drop(secret);
+        assert!(wiped.get());
+    }
+}
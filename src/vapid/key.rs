@@ -1,11 +1,18 @@
 use jwt_simple::prelude::*;
 
+use crate::secret::Secret;
+
 /// The P256 curve key pair used for VAPID ECDHSA.
 pub struct VapidKey(pub ES256KeyPair);
 
 impl Clone for VapidKey {
     fn clone(&self) -> Self {
-        VapidKey(ES256KeyPair::from_bytes(&self.0.to_bytes()).unwrap())
+        let key = copy_key(&self.0);
+
+        match self.0.key_id() {
+            Some(key_id) => VapidKey(key.with_key_id(key_id)),
+            None => VapidKey(key),
+        }
     }
 }
 
@@ -14,16 +21,30 @@ impl VapidKey {
         VapidKey(ec_key)
     }
 
+    /// Sets the `kid` put in the header of the signed tokens.
+    pub fn set_key_id(&mut self, key_id: &str) {
+        self.0 = copy_key(&self.0).with_key_id(key_id);
+    }
+
     /// Gets the uncompressed public key bytes derived from this private key.
     pub fn public_key(&self) -> Vec<u8> {
         self.0.public_key().public_key().to_bytes_uncompressed()
     }
 }
 
+/// Copies the key pair, without its key id. The private scalar goes through a [`Secret`], so the copy is wiped.
+fn copy_key(key: &ES256KeyPair) -> ES256KeyPair {
+    let bytes = Secret::new(key.to_bytes());
+
+    ES256KeyPair::from_bytes(&bytes).expect("the scalar of a valid key pair is a valid key")
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::File;
 
+    use jwt_simple::prelude::*;
+
     use crate::vapid::key::VapidKey;
 
     #[test]
@@ -54,4 +75,14 @@ mod tests {
 
         assert_eq!(key.0.to_bytes(), key2.0.to_bytes())
     }
+
+    #[test]
+    /// Tests that the key id is kept when cloning.
+    fn test_key_id_clones() {
+        let f = File::open("resources/vapid_test_key.pem").unwrap();
+        let mut key = VapidKey::new(crate::VapidSignatureBuilder::read_pem(f).unwrap());
+        key.set_key_id("2024-01");
+
+        assert_eq!(&Some(String::from("2024-01")), key.clone().0.key_id());
+    }
 }
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+
+use crate::error::WebPushError;
+use crate::message::SubscriptionInfo;
+use crate::vapid::builder::PartialVapidSignatureBuilder;
+use crate::vapid::VapidSignatureBuilder;
+
+/// A set of VAPID keys by key identifier, for rotating keys. One key is active and signs by default, but any
+/// key can be chosen per message, for example to keep using the old key for a push service until all its
+/// subscriptions have moved to the new one.
+///
+/// The key identifier is sent as the `kid` of the signed tokens.
+///
+/// ```no_run
+/// # use web_push::*;
+/// # fn main() -> Result<(), WebPushError> {
+/// # let subscription_info = SubscriptionInfo::new("https://fcm.googleapis.com/fcm/send/...", "...", "...");
+/// let mut keyring = VapidKeyring::new();
+/// keyring.add_key("2023", VapidSignatureBuilder::from_pem_no_sub(std::fs::File::open("old.pem")?)?);
+/// keyring.add_key("2024", VapidSignatureBuilder::from_pem_no_sub(std::fs::File::open("new.pem")?)?);
+/// keyring.set_active("2024");
+///
+/// //Signs with the active key.
+/// let signature = keyring.signature_builder(&subscription_info)?.build()?;
+///
+/// //Signs with a chosen key.
+/// let signature = keyring.signature_builder_for("2023", &subscription_info)?.build()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct VapidKeyring {
+    keys: BTreeMap<String, PartialVapidSignatureBuilder>,
+    active: Option<String>,
+}
+
+impl VapidKeyring {
+    /// Creates an empty keyring.
+    pub fn new() -> VapidKeyring {
+        VapidKeyring::default()
+    }
+
+    /// Adds a key, replacing any key with the same identifier. The first key added becomes the active key.
+    pub fn add_key(&mut self, key_id: &str, mut key: PartialVapidSignatureBuilder) {
+        key.set_key_id(key_id);
+        self.keys.insert(key_id.to_string(), key);
+
+        if self.active.is_none() {
+            self.active = Some(key_id.to_string());
+        }
+    }
+
+    /// Removes a key. If it was the active key, no key is active until [`VapidKeyring::set_active`] is called.
+    pub fn remove_key(&mut self, key_id: &str) {
+        self.keys.remove(key_id);
+
+        if self.active.as_deref() == Some(key_id) {
+            self.active = None;
+        }
+    }
+
+    /// Makes the key with the given identifier the one used by [`VapidKeyring::signature_builder`]. If there is
+    /// no such key, getting a builder fails.
+    pub fn set_active(&mut self, key_id: &str) {
+        self.active = Some(key_id.to_string());
+    }
+
+    /// The identifier of the active key, if any.
+    pub fn active_key_id(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// The identifiers of all the keys, in order.
+    pub fn key_ids(&self) -> impl Iterator<Item = &str> {
+        self.keys.keys().map(String::as_str)
+    }
+
+    /// Gets the public key of the key with the given identifier, uncompressed, for the `applicationServerKey`.
+    pub fn public_key(&self, key_id: &str) -> Option<Vec<u8>> {
+        self.keys.get(key_id).map(PartialVapidSignatureBuilder::get_public_key)
+    }
+
+    /// Creates a signature builder for the subscription with the active key.
+    ///
+    /// Fails with [`WebPushError::MissingCryptoKeys`] if no key is active, or the active key was removed.
+    pub fn signature_builder<'a>(
+        &self,
+        subscription_info: &'a SubscriptionInfo,
+    ) -> Result<VapidSignatureBuilder<'a>, WebPushError> {
+        let key_id = self.active.as_deref().ok_or(WebPushError::MissingCryptoKeys)?;
+
+        self.keys
+            .get(key_id)
+            .map(|key| key.clone().add_sub_info(subscription_info))
+            .ok_or(WebPushError::MissingCryptoKeys)
+    }
+
+    /// Creates a signature builder for the subscription with the key with the given identifier.
+    ///
+    /// Fails with [`WebPushError::MissingCryptoKeys`] if there is no such key.
+    pub fn signature_builder_for<'a>(
+        &self,
+        key_id: &str,
+        subscription_info: &'a SubscriptionInfo,
+    ) -> Result<VapidSignatureBuilder<'a>, WebPushError> {
+        self.keys
+            .get(key_id)
+            .map(|key| key.clone().add_sub_info(subscription_info))
+            .ok_or(WebPushError::MissingCryptoKeys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use crate::error::WebPushError;
+    use crate::fixtures::subscription_info;
+    use crate::vapid::{VapidKeyring, VapidSignatureBuilder};
+
+    fn keyring() -> VapidKeyring {
+        let old = VapidSignatureBuilder::from_pem_no_sub(File::open("resources/vapid_test_key.pem").unwrap()).unwrap();
+        let new_keys = VapidSignatureBuilder::generate_keypair().unwrap();
+        let new = VapidSignatureBuilder::from_pem_no_sub(new_keys.private_key_pem.as_bytes()).unwrap();
+
+        let mut keyring = VapidKeyring::new();
+        keyring.add_key("old", old);
+        keyring.add_key("new", new);
+        keyring
+    }
+
+    fn token_header(token: &str) -> serde_json::Value {
+        let header = token.split('.').next().unwrap();
+        serde_json::from_slice(&base64::decode_config(header, base64::URL_SAFE_NO_PAD).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn signs_with_the_active_key() {
+        let mut keyring = keyring();
+        let info = subscription_info();
+
+        assert_eq!(Some("old"), keyring.active_key_id());
+        assert_eq!(vec!["new", "old"], keyring.key_ids().collect::<Vec<_>>());
+
+        let signature = keyring.signature_builder(&info).unwrap().build().unwrap();
+
+        assert_eq!(keyring.public_key("old").unwrap(), signature.auth_k);
+        assert_eq!("old", token_header(&signature.auth_t)["kid"]);
+
+        keyring.set_active("new");
+        let signature = keyring.signature_builder(&info).unwrap().build().unwrap();
+
+        assert_eq!(keyring.public_key("new").unwrap(), signature.auth_k);
+        assert_eq!("new", token_header(&signature.auth_t)["kid"]);
+    }
+
+    #[test]
+    fn signs_with_a_chosen_key() {
+        let keyring = keyring();
+        let info = subscription_info();
+
+        let signature = keyring.signature_builder_for("new", &info).unwrap().build().unwrap();
+
+        assert_eq!(keyring.public_key("new").unwrap(), signature.auth_k);
+        assert_eq!(
+            Some(WebPushError::MissingCryptoKeys),
+            keyring.signature_builder_for("missing", &info).err()
+        );
+    }
+
+    #[test]
+    fn fails_without_an_active_key() {
+        let mut keyring = keyring();
+        let info = subscription_info();
+
+        keyring.remove_key("old");
+
+        assert_eq!(None, keyring.active_key_id());
+        assert_eq!(
+            Some(WebPushError::MissingCryptoKeys),
+            keyring.signature_builder(&info).err()
+        );
+        assert_eq!(
+            Some(WebPushError::MissingCryptoKeys),
+            VapidKeyring::new().signature_builder(&info).err()
+        );
+    }
+}
@@ -2,10 +2,11 @@
 
 pub use self::builder::VapidSignatureBuilder;
 use self::key::VapidKey;
-pub use self::signer::Claims;
+pub use self::keyring::VapidKeyring;
 pub use self::signer::VapidSignature;
 use self::signer::VapidSigner;
 
 pub mod builder;
 mod key;
+mod keyring;
 mod signer;
@@ -1,4 +1,5 @@
 use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use http::uri::Uri;
 use jwt_simple::prelude::*;
@@ -8,41 +9,116 @@ use crate::{error::WebPushError, vapid::VapidKey};
 
 /// A struct representing a VAPID signature. Should be generated using the
 /// [VapidSignatureBuilder](struct.VapidSignatureBuilder.html).
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+///
+/// A signature is valid for every message sent to the same push service origin until it expires, so it
+/// can be signed once and cloned into many messages:
+///
+/// ```no_run
+/// # use web_push::*;
+/// # fn main() -> Result<(), WebPushError> {
+/// # let subscriptions: Vec<SubscriptionInfo> = vec![];
+/// # let first = SubscriptionInfo::new("https://fcm.googleapis.com/fcm/send/...", "...", "...");
+/// //Sign once for the origin of the push service.
+/// let signature = VapidSignatureBuilder::from_pem_file("private.pem", &first)?.build()?;
+///
+/// for subscription in &subscriptions {
+///     let mut builder = WebPushMessageBuilder::new(subscription)?;
+///     builder.set_vapid_signature(signature.clone());
+///     //Add payload, build and send.
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize, Serialize)]
 pub struct VapidSignature {
-    /// The signed JWT, base64 encoded
+    /// The signed JWT, base64 encoded. This is the `t` value of the `Authorization` header. The JWT header has
+    /// `alg` `ES256` and `typ` `JWT`, and the signature is the raw 64 byte form of JWS, not DER.
     pub auth_t: String,
-    /// The public key bytes
+    /// The uncompressed public key bytes. Base64 URL safe encoded, these are the `k` value of the
+    /// `Authorization` header.
     pub auth_k: Vec<u8>,
 }
 
+impl VapidSignature {
+    /// When the token expires, read from its `exp` claim. `None` if the token can't be decoded or has no `exp`,
+    /// which doesn't happen for signatures from [VapidSignatureBuilder](struct.VapidSignatureBuilder.html).
+    pub fn expires_at(&self) -> Option<SystemTime> {
+        let exp = serde_json::from_slice::<Value>(&self.payload()?).ok()?["exp"].as_u64()?;
+
+        UNIX_EPOCH.checked_add(std::time::Duration::from_secs(exp))
+    }
+
+    /// How long until the token expires, for refreshing a cached signature in time. Tokens that already expired
+    /// have zero time remaining. `None` in the same cases as [`VapidSignature::expires_at`].
+    pub fn time_remaining(&self) -> Option<std::time::Duration> {
+        let expires_at = self.expires_at()?;
+
+        Some(expires_at.duration_since(SystemTime::now()).unwrap_or_default())
+    }
+
+    /// The claims of the token, or `None` if it can't be decoded.
+    pub(crate) fn claims(&self) -> Option<Claims> {
+        serde_json::from_slice(&self.payload()?).ok()
+    }
+
+    /// The decoded JSON payload of the token, holding the claims.
+    fn payload(&self) -> Option<Vec<u8>> {
+        let payload = self.auth_t.split('.').nth(1)?;
+
+        base64::decode_config(payload, base64::URL_SAFE_NO_PAD).ok()
+    }
+}
+
 /// JWT claims object. Custom claims are implemented as a map.
 pub type Claims = JWTClaims<BTreeMap<String /*Use String as lifetimes bug out when serializing a tuple*/, Value>>;
 
+/// How long a signature is valid for, unless set otherwise.
+pub const DEFAULT_EXPIRATION: std::time::Duration = std::time::Duration::from_secs(12 * 60 * 60);
+
+/// The longest expiration push services accept, as per the VAPID spec.
+pub const MAX_EXPIRATION: std::time::Duration = std::time::Duration::from_secs(24 * 60 * 60);
+
 pub struct VapidSigner {}
 
 impl VapidSigner {
     /// Create a signature with a given key. Sets the default audience from the
     /// endpoint host and sets the expiry in twelve hours. Values can be
     /// overwritten by adding the `aud` and `exp` claims.
-    pub fn sign(key: VapidKey, endpoint: &Uri, mut claims: Claims) -> Result<VapidSignature, WebPushError> {
-        if !claims.custom.contains_key("aud") {
-            //Add audience if not provided.
-            let audience = format!("{}://{}", endpoint.scheme_str().unwrap(), endpoint.host().unwrap());
-            claims = claims.with_audience(audience);
-        } else {
+    ///
+    /// The reserved claims `aud` and `sub` must be strings, and `exp` must be a unix timestamp
+    /// in the next 24 hours, otherwise [`WebPushError::InvalidClaims`] is returned.
+    ///
+    /// The expiry is checked against `now`, the time since the unix epoch, which is normally the current time.
+    pub fn sign(
+        key: VapidKey,
+        endpoint: &Uri,
+        mut claims: Claims,
+        now: Duration,
+    ) -> Result<VapidSignature, WebPushError> {
+        if let Some(aud) = claims.custom.remove("aud") {
             //Use provided claims if given. This is here to avoid breaking changes.
-            let aud = claims.custom.get("aud").unwrap().clone();
+            let aud = aud.as_str().ok_or(WebPushError::InvalidClaims)?.to_string();
             claims = claims.with_audience(aud);
-            claims.custom.remove("aud");
+        } else {
+            //Add audience if not provided.
+            claims = claims.with_audience(Self::audience(endpoint)?);
         }
 
         //Override the exp claim if provided in custom. Must then remove from custom to avoid printing
         //Twice, as this is just for backwards compatibility.
-        if claims.custom.contains_key("exp") {
-            let exp = claims.custom.get("exp").unwrap().clone();
+        if let Some(exp) = claims.custom.remove("exp") {
             claims.expires_at = Some(Duration::from_secs(exp.as_u64().ok_or(WebPushError::InvalidClaims)?));
-            claims.custom.remove("exp");
+        }
+
+        //Tokens from the past or too far in the future get rejected by the push services.
+        match claims.expires_at {
+            Some(exp) if exp > now && exp <= now + MAX_EXPIRATION.into() => {}
+            _ => return Err(WebPushError::InvalidClaims),
+        }
+
+        if let Some(sub) = claims.custom.remove("sub") {
+            let sub = sub.as_str().ok_or(WebPushError::InvalidClaims)?.to_string();
+            claims = claims.with_subject(sub);
         }
 
         let auth_k = key.public_key();
@@ -52,7 +128,211 @@ impl VapidSigner {
 
         Ok(VapidSignature { auth_t, auth_k })
     }
+
+    /// Derives the audience from the origin of the endpoint. The path is stripped, but any
+    /// non-default port is kept, and IPv6 hosts keep their brackets. Endpoints without a scheme or host are
+    /// [`WebPushError::InvalidUri`].
+    fn audience(endpoint: &Uri) -> Result<String, WebPushError> {
+        let (scheme, host) = match (endpoint.scheme_str(), endpoint.host()) {
+            (Some(scheme), Some(host)) => (scheme, host),
+            _ => {
+                return Err(WebPushError::InvalidUri(String::from(
+                    "endpoint must be an absolute https URL",
+                )))
+            }
+        };
+
+        match endpoint.port_u16() {
+            Some(port) if !matches!((scheme, port), ("https", 443) | ("http", 80)) => {
+                Ok(format!("{}://{}:{}", scheme, host, port))
+            }
+            _ => Ok(format!("{}://{}", scheme, host)),
+        }
+    }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs::File;
+
+    use http::uri::Uri;
+    use jwt_simple::prelude::*;
+    use serde_json::Value;
+
+    use crate::error::WebPushError;
+    use crate::vapid::signer::Claims;
+    use crate::vapid::{VapidKey, VapidSignature, VapidSigner};
+
+    fn key() -> VapidKey {
+        let f = File::open("resources/vapid_test_key.pem").unwrap();
+        VapidKey::new(crate::VapidSignatureBuilder::read_pem(f).unwrap())
+    }
+
+    fn claims() -> Claims {
+        jwt_simple::prelude::Claims::with_custom_claims(BTreeMap::new(), Duration::from_hours(12))
+    }
+
+    /// Decodes the claims section of a signed JWT.
+    fn decode_claims(token: &str) -> Value {
+        let payload = token.split('.').nth(1).unwrap();
+        serde_json::from_slice(&base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sign_custom_claims() {
+        let endpoint: Uri = "https://updates.push.services.mozilla.com/wpush/v2/foo"
+            .parse()
+            .unwrap();
+        let mut claims = claims();
+        claims
+            .custom
+            .insert("sub".to_string(), "mailto:admin@example.com".into());
+        claims.custom.insert("audit_id".to_string(), 42.into());
+
+        let signature = VapidSigner::sign(key(), &endpoint, claims, Clock::now_since_epoch()).unwrap();
+        let decoded = decode_claims(&signature.auth_t);
+
+        assert_eq!("mailto:admin@example.com", decoded["sub"]);
+        assert_eq!(42, decoded["audit_id"]);
+        assert_eq!("https://updates.push.services.mozilla.com", decoded["aud"]);
+    }
+
+    #[test]
+    fn test_sign_aud_override() {
+        let endpoint: Uri = "https://updates.push.services.mozilla.com/wpush/v2/foo"
+            .parse()
+            .unwrap();
+        let mut claims = claims();
+        claims.custom.insert("aud".to_string(), "https://example.com".into());
+
+        let signature = VapidSigner::sign(key(), &endpoint, claims, Clock::now_since_epoch()).unwrap();
+
+        assert_eq!("https://example.com", decode_claims(&signature.auth_t)["aud"]);
+    }
+
+    #[test]
+    fn test_sign_rejects_invalid_reserved_claims() {
+        let endpoint: Uri = "https://updates.push.services.mozilla.com/wpush/v2/foo"
+            .parse()
+            .unwrap();
+
+        for (name, value) in [
+            ("exp", Value::from("tomorrow")),
+            ("aud", 1.into()),
+            ("sub", true.into()),
+        ] {
+            let mut claims = claims();
+            claims.custom.insert(name.to_string(), value);
+
+            assert_eq!(
+                Err(WebPushError::InvalidClaims),
+                VapidSigner::sign(key(), &endpoint, claims, Clock::now_since_epoch()),
+                "claim {}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_audience_from_endpoint() {
+        let cases = [
+            ("https://fcm.googleapis.com/fcm/send/foo", "https://fcm.googleapis.com"),
+            (
+                "https://fcm.googleapis.com:443/fcm/send/foo",
+                "https://fcm.googleapis.com",
+            ),
+            (
+                "https://push.example.com:8443/push/foo",
+                "https://push.example.com:8443",
+            ),
+            ("https://[::1]:8443/push/foo", "https://[::1]:8443"),
+            ("https://[2001:db8::1]/push/foo", "https://[2001:db8::1]"),
+            ("https://[2001:db8::1]:443/push/foo", "https://[2001:db8::1]"),
+            ("https://127.0.0.1:8443/push/foo", "https://127.0.0.1:8443"),
+        ];
+
+        for (endpoint, audience) in cases {
+            let endpoint: Uri = endpoint.parse().unwrap();
+            assert_eq!(Ok(audience.to_string()), VapidSigner::audience(&endpoint));
+        }
+    }
+
+    #[test]
+    fn test_audience_from_relative_endpoint() {
+        let endpoint: Uri = "/push/foo".parse().unwrap();
+
+        assert_eq!(
+            Err(WebPushError::InvalidUri(String::from(
+                "endpoint must be an absolute https URL"
+            ))),
+            VapidSigner::sign(key(), &endpoint, claims(), Clock::now_since_epoch())
+        );
+    }
+
+    #[test]
+    fn test_signature_expiration() {
+        let endpoint: Uri = "https://updates.push.services.mozilla.com/wpush/v2/foo"
+            .parse()
+            .unwrap();
+        let now = Clock::now_since_epoch();
+
+        let signature = VapidSigner::sign(key(), &endpoint, claims(), now).unwrap();
+        let remaining = signature.time_remaining().unwrap();
+
+        assert_eq!(
+            decode_claims(&signature.auth_t)["exp"],
+            signature
+                .expires_at()
+                .unwrap()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        );
+        assert!(remaining <= Duration::from_hours(12).into() && remaining > Duration::from_hours(11).into());
+
+        //Signed two hours ago, valid for one hour.
+        let past = now - Duration::from_hours(2);
+        let mut claims = claims();
+        claims.expires_at = Some(past + Duration::from_hours(1));
+
+        let expired = VapidSigner::sign(key(), &endpoint, claims, past).unwrap();
+
+        assert_eq!(Some(std::time::Duration::ZERO), expired.time_remaining());
+
+        let garbage = VapidSignature {
+            auth_t: String::from("not a token"),
+            auth_k: Vec::new(),
+        };
+
+        assert_eq!(None, garbage.expires_at());
+        assert_eq!(None, garbage.time_remaining());
+    }
+
+    #[test]
+    fn test_sign_jwt_header_and_raw_signature() {
+        let endpoint: Uri = "https://updates.push.services.mozilla.com/wpush/v2/foo"
+            .parse()
+            .unwrap();
+
+        let signature = VapidSigner::sign(key(), &endpoint, claims(), Clock::now_since_epoch()).unwrap();
+        let parts: Vec<&str> = signature.auth_t.split('.').collect();
+
+        assert_eq!(3, parts.len());
+
+        let header: Value =
+            serde_json::from_slice(&base64::decode_config(parts[0], base64::URL_SAFE_NO_PAD).unwrap()).unwrap();
+
+        assert_eq!("ES256", header["alg"]);
+        assert_eq!("JWT", header["typ"]);
+
+        //Push services want the raw R || S form of JWS, a DER encoded signature would be 70 to 72 bytes.
+        let raw = base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD).unwrap();
+
+        assert_eq!(64, raw.len());
+
+        let public_key = ES256PublicKey::from_bytes(&signature.auth_k).unwrap();
+
+        assert!(public_key.verify_token::<Value>(&signature.auth_t, None).is_ok());
+    }
+}